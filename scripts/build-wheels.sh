@@ -4,7 +4,7 @@
 # https://pyo3.github.io/pyo3/guide/distribution.html#binary-wheel-distribution
 set -e -x
 
-yum install -y gpg libXtst libXtst-devel libXext libXext-devel
+yum install -y gpg libXtst libXtst-devel libXext libXext-devel libXinerama-devel libXrandr-devel
 
 mkdir ~/rust-installer
 curl -sL https://static.rust-lang.org/rustup.sh -o ~/rust-installer/rustup.sh
@@ -0,0 +1,160 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Encoding of timestamped frames as animated GIF or APNG, keeping the time
+//! between frames.
+
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::error::{EncodingError, ImageFormatHint};
+use image::imageops::FilterType;
+use image::{Delay, DynamicImage, ImageError, ImageFormat, ImageResult, RgbaImage};
+use std::io::Write;
+
+/// How long the last frame is shown if there is only one frame, and so no
+/// interval between frames to go by.
+const DEFAULT_DELAY_MS: u64 = 100;
+
+/// Trades GIF palette quality for encoding speed, from 1 (best quality) to 30.
+const GIF_SPEED: i32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AnimationFormat {
+    Gif,
+    Apng,
+}
+
+impl AnimationFormat {
+    pub fn from_name(name: &str) -> Option<AnimationFormat> {
+        match name.to_lowercase().as_str() {
+            "gif" => Some(AnimationFormat::Gif),
+            "apng" | "png" => Some(AnimationFormat::Apng),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnimationOptions {
+    /// Whether to merge consecutive identical frames into one frame shown for
+    /// their combined duration.
+    pub dedup: bool,
+    /// Factor to resize frames by.
+    pub scale: f64,
+    /// Number of times the animation plays, or 0 to loop forever.
+    pub loops: u16,
+}
+
+/// Encodes frames, given as `(timestamp, image)` pairs in seconds and of the
+/// same size, as an animation. Each frame is shown until the next one's
+/// timestamp, and the last for the same time as the frame before it.
+pub fn encode<W: Write>(
+    writer: W,
+    frames: &[(f64, &DynamicImage)],
+    format: AnimationFormat,
+    options: AnimationOptions,
+) -> ImageResult<()> {
+    let timeline = timeline(frames, options);
+    match format {
+        AnimationFormat::Gif => encode_gif(writer, timeline, options.loops),
+        AnimationFormat::Apng => encode_apng(writer, timeline, options.loops),
+    }
+}
+
+/// Returns the frames to encode along with how long to show each, in
+/// milliseconds.
+fn timeline(frames: &[(f64, &DynamicImage)], options: AnimationOptions) -> Vec<(RgbaImage, u64)> {
+    let millis = |timestamp: f64| (timestamp * 1000.0).round().max(0.0) as u64;
+    let end = match frames {
+        [.., (previous, _), (last, _)] => {
+            let last = millis(*last);
+            last + last.saturating_sub(millis(*previous))
+        }
+        [(last, _)] => millis(*last) + DEFAULT_DELAY_MS,
+        [] => return Vec::new(),
+    };
+
+    let mut kept: Vec<(f64, &DynamicImage)> = Vec::with_capacity(frames.len());
+    for &(timestamp, image) in frames {
+        let duplicate = kept
+            .last()
+            .is_some_and(|(_, previous)| previous.as_bytes() == image.as_bytes());
+        if !(options.dedup && duplicate) {
+            kept.push((timestamp, image));
+        }
+    }
+
+    let ends = kept.iter().skip(1).map(|&(timestamp, _)| millis(timestamp));
+    kept.iter()
+        .zip(ends.chain(std::iter::once(end)))
+        .map(|(&(timestamp, image), end)| {
+            let delay = end.saturating_sub(millis(timestamp));
+            (scaled(image, options.scale), delay)
+        })
+        .collect()
+}
+
+fn scaled(image: &DynamicImage, scale: f64) -> RgbaImage {
+    if scale == 1.0 {
+        return image.to_rgba8();
+    }
+    let width = (image.width() as f64 * scale).round().max(1.0) as u32;
+    let height = (image.height() as f64 * scale).round().max(1.0) as u32;
+    image
+        .resize_exact(width, height, FilterType::Triangle)
+        .to_rgba8()
+}
+
+fn encode_gif<W: Write>(writer: W, timeline: Vec<(RgbaImage, u64)>, loops: u16) -> ImageResult<()> {
+    let mut encoder = GifEncoder::new_with_speed(writer, GIF_SPEED);
+    encoder.set_repeat(match loops {
+        0 => Repeat::Infinite,
+        loops => Repeat::Finite(loops - 1),
+    })?;
+    for (image, delay) in timeline {
+        let delay = Delay::from_numer_denom_ms(delay.min(u32::MAX as u64) as u32, 1);
+        encoder.encode_frame(image::Frame::from_parts(image, 0, 0, delay))?;
+    }
+    Ok(())
+}
+
+fn encode_apng<W: Write>(
+    writer: W,
+    timeline: Vec<(RgbaImage, u64)>,
+    loops: u16,
+) -> ImageResult<()> {
+    let Some((first, _)) = timeline.first() else {
+        return Ok(());
+    };
+    let mut encoder = png::Encoder::new(writer, first.width(), first.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .set_animated(timeline.len() as u32, loops as u32)
+        .map_err(png_error)?;
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    for (image, delay) in &timeline {
+        // Delays are fractions with 16-bit terms, so use coarser units for
+        // frames shown for over a minute.
+        let (numerator, denominator) = if *delay <= u16::MAX as u64 {
+            (*delay as u16, 1000)
+        } else {
+            ((delay / 10).min(u16::MAX as u64) as u16, 100)
+        };
+        writer
+            .set_frame_delay(numerator, denominator)
+            .map_err(png_error)?;
+        writer.write_image_data(image.as_raw()).map_err(png_error)?;
+    }
+    writer.finish().map_err(png_error)
+}
+
+fn png_error(err: png::EncodingError) -> ImageError {
+    ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::Png),
+        err,
+    ))
+}
@@ -6,109 +6,261 @@
 // copied, modified, or distributed except according to those terms.
 
 use autopilot::geometry::{Point, Rect, Size};
-use image::Pixel;
-use image::{ImageFormat, ImageResult, Rgba};
-use crate::internal::{rgb_to_hex, hex_to_rgb, FromImageError};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image::codecs::pnm::{PnmEncoder, PnmSubtype, SampleEncoding};
+use image::{GenericImage, Pixel};
+use image::imageops;
+use image::{
+    ColorType, DynamicImage, GrayImage, ImageBuffer, ImageFormat, ImageResult, Rgba, RgbaImage,
+};
+use crate::animation::{self, AnimationFormat, AnimationOptions};
+use crate::display;
+use crate::filters::{self, Filter};
+use crate::hashing::{self, HashMethod};
+use crate::internal::{self, rgb_to_hex, hex_to_rgb, FromImageError, TimeoutError};
+use crate::matching::{
+    self, ColorQuery, Haystack, Match, MatchMethod, Region, Suppression, Template,
+};
+use crate::palette;
+use crate::regions;
+use crate::screen;
+use crate::stream;
 use pyo3::basic::CompareOp;
+use pyo3::buffer::PyBuffer;
+use pyo3::marker::Ungil;
 use pyo3::prelude::*;
-use pyo3::types::PyType;
+use pyo3::IntoPyObjectExt;
+use pyo3::types::{PyBytes, PyDict, PyTuple, PyType};
+use std::borrow::Cow;
 use std::collections::hash_map::DefaultHasher;
-use std::fs::File;
+use std::ffi::CStr;
 use std::hash::{Hash, Hasher};
-use std::path::Path;
+use std::io::{Cursor, Seek, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 #[pyclass]
 struct Bitmap {
     bitmap: autopilot::bitmap::Bitmap,
+    /// Number of live writable buffer exports, through which Python code may
+    /// change pixels behind the bitmap's back.
+    writable_exports: AtomicUsize,
 }
 
 #[pymethods]
 impl Bitmap {
-    fn __richcmp__(&self, other: &Bitmap, op: CompareOp) -> PyResult<bool> {
+    // Bitmaps have no natural ordering, so only (in)equality is supported.
+    // Comparisons with other types also return `NotImplemented`, which PyO3
+    // does automatically when `other` isn't a `Bitmap`.
+    fn __richcmp__(&self, py: Python<'_>, other: &Bitmap, op: CompareOp) -> PyResult<Py<PyAny>> {
         match op {
-            CompareOp::Eq => Ok(self.bitmap == other.bitmap),
-            _ => unimplemented!(),
+            CompareOp::Eq => (self.bitmap == other.bitmap).into_py_any(py),
+            CompareOp::Ne => (self.bitmap != other.bitmap).into_py_any(py),
+            _ => Ok(py.NotImplemented()),
         }
     }
 
+    // Hashes the pixels, as equality compares them, so bitmaps can be kept in
+    // sets and used as dict keys. As with any key, a bitmap modified in place
+    // afterwards won't be found again.
     fn __hash__(&self) -> PyResult<isize> {
         let mut s = DefaultHasher::new();
         self.bitmap.hash(&mut s);
         Ok(s.finish() as isize)
     }
 
-    // From
+    // Adapted from
     // https://github.com/PyO3/pyo3/blob/97189a1/tests/test_buffer_protocol.rs#L17
-    unsafe fn __getbuffer__(&self, view: *mut pyo3::ffi::Py_buffer, flags: libc::c_int) -> PyResult<()> {
+    //
+    // Pixels are exported as a C-contiguous array of shape `(height, width,
+    // channels)`, or `(height, width)` for grayscale images, so that
+    // `numpy.asarray(bmp)` needs no reshaping.
+    unsafe fn __getbuffer__(
+        slf: Bound<'_, Self>,
+        view: *mut pyo3::ffi::Py_buffer,
+        flags: libc::c_int,
+    ) -> PyResult<()> {
         use pyo3::exceptions::PyBufferError;
-        use pyo3::*;
-        use std::ffi::CStr;
+        use pyo3::ffi;
         use std::ptr;
 
         if view.is_null() {
             return Err(PyBufferError::new_err("View is null"));
         }
 
-        unsafe {
-            (*view).obj = ptr::null_mut();
-        }
-
-        if (flags & ffi::PyBUF_WRITABLE) == ffi::PyBUF_WRITABLE {
-            return Err(PyBufferError::new_err("Object is not writable"));
-        }
+        // Pixels may only be written through the buffer if nothing else, such
+        // as a search running without the GIL, is using the bitmap. Once
+        // exported, methods that modify the bitmap are rejected and searches
+        // keep the GIL until the buffer is released.
+        let writable = (flags & ffi::PyBUF_WRITABLE) == ffi::PyBUF_WRITABLE;
+        let (buf, len, layout) = if writable {
+            let mut bmp = slf.try_borrow_mut()?;
+            let layout = PixelLayout::new(&bmp.bitmap.image);
+            let bytes = image_bytes_mut(&mut bmp.bitmap.image);
+            let buf = (bytes.as_mut_ptr(), bytes.len());
+            bmp.writable_exports.fetch_add(1, Ordering::Relaxed);
+            (buf.0, buf.1, layout)
+        } else {
+            let bmp = slf.try_borrow()?;
+            let bytes = bmp.bitmap.image.as_bytes();
+            let layout = PixelLayout::new(&bmp.bitmap.image);
+            (bytes.as_ptr() as *mut u8, bytes.len(), layout)
+        };
+        // Consumers that don't ask for a format assume unsigned bytes, so the
+        // item size must then be 1 as well.
+        let layout = if (flags & ffi::PyBUF_FORMAT) == ffi::PyBUF_FORMAT {
+            layout
+        } else {
+            layout.into_bytes()
+        };
 
-        let bytes = &self.bitmap.image.as_bytes();
+        // Shape and strides must outlive this call, so they are kept alive in
+        // `internal` until `__releasebuffer__` is invoked.
+        let dimensions: *mut [isize; 6] = Box::into_raw(Box::new(layout.dimensions()));
 
         unsafe {
-            (*view).buf = bytes.as_ptr() as *mut libc::c_void;
-            (*view).len = bytes.len() as isize;
-            (*view).readonly = 1;
-            (*view).itemsize = 1;
+            (*view).buf = buf as *mut libc::c_void;
+            (*view).len = len as isize;
+            (*view).readonly = if writable { 0 } else { 1 };
+            (*view).itemsize = layout.itemsize;
 
             (*view).format = ptr::null_mut();
             if (flags & ffi::PyBUF_FORMAT) == ffi::PyBUF_FORMAT {
-                let msg = CStr::from_bytes_with_nul(b"B\0").unwrap();
-                (*view).format = msg.as_ptr() as *mut _;
+                (*view).format = layout.format.as_ptr() as *mut _;
             }
 
-            (*view).ndim = 1;
+            (*view).ndim = layout.ndim as libc::c_int;
             (*view).shape = ptr::null_mut();
             if (flags & ffi::PyBUF_ND) == ffi::PyBUF_ND {
-                (*view).shape = (&((*view).len)) as *const _ as *mut _;
+                (*view).shape = (*dimensions).as_mut_ptr();
+            } else {
+                // Without shape information consumers see a flat run of
+                // items.
+                (*view).ndim = 1;
             }
 
             (*view).strides = ptr::null_mut();
             if (flags & ffi::PyBUF_STRIDES) == ffi::PyBUF_STRIDES {
-                (*view).strides = &((*view).itemsize) as *const _ as *mut _;
+                (*view).strides = (*dimensions).as_mut_ptr().add(3);
             }
 
             (*view).suboffsets = ptr::null_mut();
-            (*view).internal = ptr::null_mut();
+            (*view).internal = dimensions as *mut libc::c_void;
+            (*view).obj = slf.clone().into_any().into_ptr();
         }
 
         Ok(())
     }
 
+    unsafe fn __releasebuffer__(&self, view: *mut pyo3::ffi::Py_buffer) {
+        unsafe {
+            if (*view).readonly == 0 {
+                self.writable_exports.fetch_sub(1, Ordering::Relaxed);
+            }
+            let dimensions = (*view).internal as *mut [isize; 6];
+            if !dimensions.is_null() {
+                drop(Box::from_raw(dimensions));
+                (*view).internal = std::ptr::null_mut();
+            }
+        }
+    }
+
+    /// Describes the bitmap's pixel data using the NumPy array interface, so
+    /// that `numpy.asarray(bmp)` returns an array of shape `(height, width,
+    /// channels)`, or `(height, width)` for grayscale bitmaps.
+    #[getter(__array_interface__)]
+    fn array_interface<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, PyDict>> {
+        let py = slf.py();
+        let bmp = slf.borrow();
+        let layout = PixelLayout::new(&bmp.bitmap.image);
+        let dimensions = layout.dimensions();
+        let shape = PyTuple::new(py, &dimensions[..layout.ndim])?;
+        let strides = PyTuple::new(py, &dimensions[3..3 + layout.ndim])?;
+        let data = bmp.bitmap.image.as_bytes().as_ptr() as usize;
+        let interface = PyDict::new(py);
+        interface.set_item("version", 3)?;
+        interface.set_item("shape", shape)?;
+        interface.set_item("typestr", layout.typestr)?;
+        interface.set_item("data", (data, true))?;
+        interface.set_item("strides", strides)?;
+        Ok(interface)
+    }
+
     /// Saves image to absolute path in the given format. The image type is
     /// determined from the filename if possible, unless format is given. If
-    /// the file already exists, it will be overwritten. Currently only jpeg
-    /// and png files are supported.
+    /// the file already exists, it will be overwritten. Supported formats are
+    /// bmp, gif, ico, jpeg, png, pnm, qoi, tga, tiff and webp (lossless).
+    /// ICO images can be at most 256x256 pixels, and PNM files other than PAM
+    /// are written without an alpha channel.
+    ///
+    /// Instead of a path, any object with a binary `write()` method (e.g.
+    /// `io.BytesIO` or a socket file) may be given. The format is then taken
+    /// from the object's `name` attribute if it has one.
+    ///
+    /// `quality` sets the JPEG quality in the range 1 - 100, and `compression`
+    /// sets the PNG compression level to one of "fast", "default" or "best".
+    /// Both are ignored for other formats.
     ///
     /// Exceptions:
     ///     - `IOError` is thrown if the file could not be saved.
-    ///     - `ValueError` is thrown if image couldn't be parsed.
-    #[pyo3(signature = (path, format=None))]
-    fn save(&self, path: &str, format: Option<&str>) -> PyResult<()> {
-        let format = format
-            .or(Path::new(path).extension().and_then(|x| x.to_str()))
-            .unwrap_or("");
-        let fmt = image_output_format_from_extension(format);
-        let ref mut buffer = File::create(path)?;
-        self.bitmap
-            .image
-            .write_to(buffer, ImageFormat::try_from(fmt)?)
-            .map_err(FromImageError::from)?;
-        Ok(())
+    ///     - `ValueError` is thrown if image couldn't be parsed, or is too
+    ///       large for the format.
+    ///     - `TypeError` is thrown if `path` is neither a path nor writable.
+    #[pyo3(signature = (path, format=None, quality=None, compression=None))]
+    fn save(
+        &self,
+        path: &Bound<'_, PyAny>,
+        format: Option<&str>,
+        quality: Option<i64>,
+        compression: Option<&str>,
+    ) -> PyResult<()> {
+        let options = EncoderOptions::new(quality, compression)?;
+        if let Ok(file_path) = path.extract::<PathBuf>() {
+            let format = format
+                .or(file_path.extension().and_then(|x| x.to_str()))
+                .unwrap_or("");
+            // Encoded up front, so that nothing is written if the image can't
+            // be encoded in this format.
+            let bytes = self.encoded(format, &options)?;
+            std::fs::write(file_path, bytes)?;
+            Ok(())
+        } else if path.hasattr("write")? {
+            let name: Option<PathBuf> = path
+                .getattr("name")
+                .ok()
+                .and_then(|name| name.extract().ok());
+            let format = format
+                .or(name.as_ref().and_then(|x| x.extension()).and_then(|x| x.to_str()))
+                .unwrap_or("");
+            let bytes = self.encoded(format, &options)?;
+            path.call_method1("write", (PyBytes::new(path.py(), &bytes),))?;
+            Ok(())
+        } else {
+            Err(pyo3::exceptions::PyTypeError::new_err(
+                "Expected path or file-like object",
+            ))
+        }
+    }
+
+    /// Returns the image encoded in the given format, e.g. "png", as `bytes`.
+    /// `quality` and `compression` are as described for `save`.
+    ///
+    /// Exceptions:
+    ///     - `IOError` is thrown if the image could not be encoded.
+    ///     - `ValueError` is thrown if the format is unsupported.
+    #[pyo3(signature = (format, quality=None, compression=None))]
+    fn encode<'py>(
+        &self,
+        py: Python<'py>,
+        format: &str,
+        quality: Option<i64>,
+        compression: Option<&str>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let options = EncoderOptions::new(quality, compression)?;
+        let bytes = self.encoded(format, &options)?;
+        Ok(PyBytes::new(py, &bytes))
     }
 
     /// Copies image to pasteboard. Currently only supported on macOS.
@@ -144,7 +296,110 @@ impl Bitmap {
     fn open(cls: &Bound<'_, PyType>, path: String) -> PyResult<Py<Bitmap>> {
         let image = image::open(path).map_err(FromImageError::from)?;
         let bmp = autopilot::bitmap::Bitmap::new(image, None);
-        let result = Py::new(cls.py(), Bitmap { bitmap: bmp })?;
+        let result = Py::new(cls.py(), Bitmap::new(bmp))?;
+        Ok(result)
+    }
+
+    /// Creates a bitmap from raw, uncompressed pixel data. `data` may be any
+    /// object supporting the buffer protocol, e.g. `bytes` or `bytearray`.
+    /// `mode` describes the layout of each pixel and is one of "L", "LA",
+    /// "RGB", "RGBA", "BGR" or "BGRA". `stride` is the number of bytes per
+    /// row, and defaults to `width` times the number of channels.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the mode is unknown or `data` is too
+    ///       short for the given dimensions.
+    #[classmethod]
+    #[pyo3(signature = (data, width, height, mode="RGB", stride=None))]
+    fn from_bytes(
+        cls: &Bound<'_, PyType>,
+        data: PyBuffer<u8>,
+        width: u32,
+        height: u32,
+        mode: &str,
+        stride: Option<usize>,
+    ) -> PyResult<Py<Bitmap>> {
+        let mode = PixelMode::from_name(mode)?;
+        let bytes = data.to_vec(cls.py())?;
+        let image = image_from_raw(&bytes, width, height, mode, stride)?;
+        let bmp = autopilot::bitmap::Bitmap::new(image, None);
+        let result = Py::new(cls.py(), Bitmap::new(bmp))?;
+        Ok(result)
+    }
+
+    /// Creates a bitmap from an object supporting the buffer protocol with
+    /// unsigned byte elements, such as a NumPy array of `dtype=uint8`. The
+    /// buffer must have shape `(height, width)` for grayscale data or
+    /// `(height, width, channels)` otherwise. If `mode` is `None`, it is
+    /// inferred from the number of channels; pass "BGR" or "BGRA" for data
+    /// coming from OpenCV.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the buffer's shape is unsupported or
+    ///       does not agree with `mode`.
+    #[classmethod]
+    #[pyo3(signature = (buffer, mode=None))]
+    fn from_buffer(
+        cls: &Bound<'_, PyType>,
+        buffer: PyBuffer<u8>,
+        mode: Option<&str>,
+    ) -> PyResult<Py<Bitmap>> {
+        let (height, width, channels) = match *buffer.shape() {
+            [height, width] => (height, width, 1),
+            [height, width, channels] => (height, width, channels),
+            _ => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Expected a buffer of shape (height, width) or (height, width, channels), got {:?}",
+                    buffer.shape()
+                )));
+            }
+        };
+        let mode = match mode {
+            Some(mode) => PixelMode::from_name(mode)?,
+            None => PixelMode::from_channels(channels)?,
+        };
+        if mode.channels() != channels {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Mode has {} channels but buffer has {}",
+                mode.channels(),
+                channels
+            )));
+        }
+        // Copies into C-contiguous order, so strided views (e.g. NumPy
+        // slices) are handled as well.
+        let bytes = buffer.to_vec(cls.py())?;
+        let image = image_from_raw(&bytes, width as u32, height as u32, mode, None)?;
+        let bmp = autopilot::bitmap::Bitmap::new(image, None);
+        let result = Py::new(cls.py(), Bitmap::new(bmp))?;
+        Ok(result)
+    }
+
+    /// Creates a bitmap from an encoded image held in memory, e.g. the
+    /// contents of a PNG file read from an archive. The image's format is
+    /// guessed from its contents, unless format is given as a file extension
+    /// such as "png".
+    ///
+    /// Exceptions:
+    ///     - `IOError` is thrown if the image could not be decoded.
+    ///     - `ValueError` is thrown if the format is unknown.
+    #[classmethod]
+    #[pyo3(signature = (data, format=None))]
+    fn decode(
+        cls: &Bound<'_, PyType>,
+        data: PyBuffer<u8>,
+        format: Option<&str>,
+    ) -> PyResult<Py<Bitmap>> {
+        let bytes = data.to_vec(cls.py())?;
+        let image = if let Some(format) = format {
+            let format = ImageFormat::from_extension(format).ok_or_else(|| {
+                pyo3::exceptions::PyValueError::new_err(format!("Unknown image format {}", format))
+            })?;
+            image::load_from_memory_with_format(&bytes, format)
+        } else {
+            image::load_from_memory(&bytes)
+        };
+        let bmp = autopilot::bitmap::Bitmap::new(image.map_err(FromImageError::from)?, None);
+        let result = Py::new(cls.py(), Bitmap::new(bmp))?;
         Ok(result)
     }
 
@@ -166,6 +421,113 @@ impl Bitmap {
         }
     }
 
+    /// Returns the alpha value at a given point, in the range 0 (transparent)
+    /// to 255 (opaque). Bitmaps without an alpha channel are opaque.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the point out of bounds.
+    fn get_alpha(&self, x: f64, y: f64) -> PyResult<u8> {
+        let point = Point::new(x, y);
+        if !self.bitmap.bounds().is_point_visible(point) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Point out of bounds {}",
+                point
+            )));
+        }
+        Ok(self.bitmap.get_pixel(point)[3])
+    }
+
+    /// Sets the color at a given point to the given hexadecimal value. Bitmaps
+    /// with an alpha channel are made opaque at that point.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the point out of bounds.
+    ///     - `BufferError` is thrown if a writable buffer of the bitmap is
+    ///       exported, e.g. as a NumPy array.
+    fn set_color(&mut self, x: f64, y: f64, color: u32) -> PyResult<()> {
+        self.ensure_modifiable()?;
+        let point = Point::new(x, y);
+        if !self.bitmap.bounds().is_point_visible(point) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Point out of bounds {}",
+                point
+            )));
+        }
+        let point = point.scaled(self.bitmap.scale);
+        let (r, g, b) = hex_to_rgb(color);
+        self.bitmap.image.put_pixel(point.x as u32, point.y as u32, Rgba([r, g, b, 255]));
+        Ok(())
+    }
+
+    /// Fills `rect` of the form `((x, y), (width, height))` with the given
+    /// hexadecimal color, e.g. to mask out a clock before comparing bitmaps.
+    /// Bitmaps with an alpha channel are made opaque inside `rect`.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the rect is out of bounds.
+    ///     - `BufferError` is thrown if a writable buffer of the bitmap is
+    ///       exported, e.g. as a NumPy array.
+    fn fill_rect(&mut self, rect: ((f64, f64), (f64, f64)), color: u32) -> PyResult<()> {
+        self.ensure_modifiable()?;
+        let portion = Rect::new(
+            Point::new((rect.0).0, (rect.0).1),
+            Size::new((rect.1).0, (rect.1).1),
+        );
+        if !self.bitmap.bounds().is_rect_visible(portion) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Rect out of bounds {:?}",
+                rect
+            )));
+        }
+        let (x, y, width, height) = self.pixel_rect(Some(portion));
+        let (image_width, image_height) = self.pixel_size();
+        let (r, g, b) = hex_to_rgb(color);
+        for row in y..(y + height).min(image_height) {
+            for column in x..(x + width).min(image_width) {
+                self.bitmap.image.put_pixel(column, row, Rgba([r, g, b, 255]));
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the pixels of `bitmap` into the receiver with its top-left
+    /// corner at `point`, replacing rather than blending with the pixels
+    /// underneath. Pixels are copied one to one, regardless of either
+    /// bitmap's scale.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if `bitmap` does not fit inside the
+    ///       receiver at `point`.
+    ///     - `BufferError` is thrown if a writable buffer of the bitmap is
+    ///       exported, e.g. as a NumPy array.
+    fn paste(
+        slf: &Bound<'_, Self>,
+        bitmap: &Bound<'_, Bitmap>,
+        point: (f64, f64),
+    ) -> PyResult<()> {
+        // Copied up front, as `bitmap` may be the receiver itself.
+        let source = bitmap.try_borrow()?.bitmap.image.clone();
+        // Fails rather than panics if a search is reading the bitmap without
+        // the GIL.
+        let mut bmp = slf.try_borrow_mut()?;
+        bmp.ensure_modifiable()?;
+        let origin = Point::new(point.0, point.1).scaled(bmp.bitmap.scale).round();
+        let (width, height) = bmp.pixel_size();
+        if origin.x < 0.0
+            || origin.y < 0.0
+            || origin.x + source.width() as f64 > width as f64
+            || origin.y + source.height() as f64 > height as f64
+        {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Bitmap of size {:?} does not fit at point {:?}",
+                (source.width(), source.height()),
+                point
+            )));
+        }
+        imageops::replace(&mut bmp.bitmap.image, &source, origin.x as i64, origin.y as i64);
+        Ok(())
+    }
+
     /// Attempts to find `color` inside `rect` of the form `((x, y), (width,
     /// height))` in `bmp` from the given `start_point`. Returns coordinates if
     /// found, or `None` if not. If `rect` is `None`, `bmp.bounds` is used
@@ -173,72 +535,218 @@ impl Bitmap {
     ///
     /// Tolerance is defined as a float in the range from 0 to 1, where 0 is an
     /// exact match and 1 matches anything.
-    #[pyo3(signature = (color, tolerance=None, rect=None, start_point=None))]
+    ///
+    /// Colors are compared on their RGB values only, unless `alpha` (from 0
+    /// to 255) is given, in which case pixels' alpha values must also match
+    /// it within the tolerance. If `match_transparent` is `False`, pixels that
+    /// are more than half transparent never match.
+    #[pyo3(signature = (
+        color,
+        tolerance=None,
+        rect=None,
+        start_point=None,
+        alpha=None,
+        match_transparent=true
+    ))]
+    #[allow(clippy::too_many_arguments)]
     fn find_color(
         &self,
+        py: Python<'_>,
         color: u32,
         tolerance: Option<f64>,
         rect: Option<((f64, f64), (f64, f64))>,
         start_point: Option<(f64, f64)>,
+        alpha: Option<u8>,
+        match_transparent: bool,
     ) -> PyResult<Option<(f64, f64)>> {
-        let split_color = hex_to_rgb(color);
-        let rgb = Rgba([split_color.0, split_color.1, split_color.2, 255]);
+        let query = color_query(color, tolerance, alpha, match_transparent);
         let rect: Option<Rect> =
             rect.map(|r| Rect::new(Point::new((r.0).0, (r.0).1), Size::new((r.1).0, (r.1).1)));
         let start_point: Option<Point> = start_point.map(|p| Point::new(p.0, p.1));
-        if let Some(point) = self.bitmap.find_color(rgb, tolerance, rect, start_point) {
-            Ok(Some((point.x, point.y)))
-        } else {
-            Ok(None)
-        }
+        let points = detach_reading(py, [self], || {
+            self.find_every_color_in(query, rect, start_point, Some(1))
+        });
+        Ok(points.first().copied())
     }
 
     /// Returns list of all `(x, y)` coordinates inside `rect` in `bmp`
     /// matching `color` from the given `start_point`. If `rect` is `None`,
     /// `bmp.bounds` is used instead. If `start_point` is `None`, the origin of
-    /// `rect` is used.
-    #[pyo3(signature = (color, tolerance=None, rect=None, start_point=None))]
+    /// `rect` is used. `alpha` and `match_transparent` are handled as
+    /// described for `find_color`.
+    #[pyo3(signature = (
+        color,
+        tolerance=None,
+        rect=None,
+        start_point=None,
+        alpha=None,
+        match_transparent=true
+    ))]
+    #[allow(clippy::too_many_arguments)]
     fn find_every_color(
         &self,
+        py: Python<'_>,
         color: u32,
         tolerance: Option<f64>,
         rect: Option<((f64, f64), (f64, f64))>,
         start_point: Option<(f64, f64)>,
+        alpha: Option<u8>,
+        match_transparent: bool,
     ) -> PyResult<Vec<(f64, f64)>> {
-        let split_color = hex_to_rgb(color);
-        let rgb = Rgba([split_color.0, split_color.1, split_color.2, 255]);
+        let query = color_query(color, tolerance, alpha, match_transparent);
         let rect: Option<Rect> =
             rect.map(|r| Rect::new(Point::new((r.0).0, (r.0).1), Size::new((r.1).0, (r.1).1)));
         let start_point: Option<Point> = start_point.map(|p| Point::new(p.0, p.1));
-        let points = self
-            .bitmap
-            .find_every_color(rgb, tolerance, rect, start_point)
-            .iter()
-            .map(|p| (p.x, p.y))
-            .collect();
+        let points = detach_reading(py, [self], || {
+            self.find_every_color_in(query, rect, start_point, None)
+        });
         Ok(points)
     }
 
     /// Returns count of color in bitmap. Functionally equivalent to:
     ///
-    /// `len(find_every_color(color, tolerance, rect, start_point))`
-    #[pyo3(signature = (color, tolerance=None, rect=None, start_point=None))]
+    /// `len(find_every_color(color, tolerance, rect, start_point, alpha,
+    /// match_transparent))`
+    #[pyo3(signature = (
+        color,
+        tolerance=None,
+        rect=None,
+        start_point=None,
+        alpha=None,
+        match_transparent=true
+    ))]
+    #[allow(clippy::too_many_arguments)]
     fn count_of_color(
         &self,
+        py: Python<'_>,
         color: u32,
         tolerance: Option<f64>,
         rect: Option<((f64, f64), (f64, f64))>,
         start_point: Option<(f64, f64)>,
+        alpha: Option<u8>,
+        match_transparent: bool,
     ) -> PyResult<u64> {
-        let split_color = hex_to_rgb(color);
-        let rgb = Rgba([split_color.0, split_color.1, split_color.2, 255]);
+        let query = color_query(color, tolerance, alpha, match_transparent);
         let rect: Option<Rect> =
             rect.map(|r| Rect::new(Point::new((r.0).0, (r.0).1), Size::new((r.1).0, (r.1).1)));
         let start_point: Option<Point> = start_point.map(|p| Point::new(p.0, p.1));
-        let count = self
-            .bitmap
-            .count_of_color(rgb, tolerance, rect, start_point);
-        Ok(count)
+        let points = detach_reading(py, [self], || {
+            self.find_every_color_in(query, rect, start_point, None)
+        });
+        Ok(points.len() as u64)
+    }
+
+    /// Groups the pixels inside `rect` matching `color` into connected blobs
+    /// (including diagonal neighbours), e.g. to find every red badge on
+    /// screen. Returns a list of `(bounds, centroid, count)` tuples ordered
+    /// from top to bottom, where `bounds` is the blob's bounding box in the
+    /// form `((x, y), (width, height))`, `centroid` is the mean `(x, y)`
+    /// position of its pixels, and `count` is its number of pixels. Blobs
+    /// with fewer than `min_area` pixels are left out. If `rect` is `None`,
+    /// `bmp.bounds` is used instead.
+    ///
+    /// Tolerance, `alpha` and `match_transparent` are handled as described for
+    /// `find_color`.
+    #[pyo3(signature = (
+        color,
+        tolerance=None,
+        rect=None,
+        min_area=1,
+        alpha=None,
+        match_transparent=true
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn find_color_regions(
+        &self,
+        py: Python<'_>,
+        color: u32,
+        tolerance: Option<f64>,
+        rect: Option<((f64, f64), (f64, f64))>,
+        min_area: u64,
+        alpha: Option<u8>,
+        match_transparent: bool,
+    ) -> PyResult<Vec<ColorRegion>> {
+        let query = color_query(color, tolerance, alpha, match_transparent);
+        let rect: Option<Rect> =
+            rect.map(|r| Rect::new(Point::new((r.0).0, (r.0).1), Size::new((r.1).0, (r.1).1)));
+        let scale = self.bitmap.scale;
+        let (blobs, region) = detach_reading(py, [self], || {
+            let image = rgba_image(&self.bitmap.image);
+            let region = Region::new(&image, (1, 1), self.pixel_rect(rect));
+            let matches: Vec<bool> = (region.y0..region.y1)
+                .flat_map(|y| (region.x0..region.x1).map(move |x| (x, y)))
+                .map(|(x, y)| query.matches(&image.get_pixel(x, y).0))
+                .collect();
+            let mut blobs = regions::connected_regions(&matches, region.x1 - region.x0);
+            blobs.retain(|blob| blob.count >= min_area);
+            (blobs, region)
+        });
+        let to_points = |x: f64, y: f64| {
+            ((region.x0 as f64 + x) / scale, (region.y0 as f64 + y) / scale)
+        };
+        Ok(blobs
+            .into_iter()
+            .map(|blob| {
+                let origin = to_points(blob.min_x as f64, blob.min_y as f64);
+                let size = (blob.width() as f64 / scale, blob.height() as f64 / scale);
+                let (x, y) = blob.centroid();
+                ((origin, size), to_points(x, y), blob.count)
+            })
+            .collect())
+    }
+
+    /// Returns the number of pixels inside `rect` with each value of the red,
+    /// green and blue channels, as a tuple of three lists of 256 counts. If
+    /// `rect` is `None`, `bmp.bounds` is used instead. Pixels that are more
+    /// than half transparent are ignored.
+    #[pyo3(signature = (rect=None))]
+    fn histogram(
+        &self,
+        py: Python<'_>,
+        rect: Option<((f64, f64), (f64, f64))>,
+    ) -> PyResult<(Vec<u64>, Vec<u64>, Vec<u64>)> {
+        let rect: Option<Rect> =
+            rect.map(|r| Rect::new(Point::new((r.0).0, (r.0).1), Size::new((r.1).0, (r.1).1)));
+        let [red, green, blue] = detach_reading(py, [self], || {
+            let image = rgba_image(&self.bitmap.image);
+            let region = Region::new(&image, (1, 1), self.pixel_rect(rect));
+            palette::histogram(&image, region)
+        });
+        Ok((red.to_vec(), green.to_vec(), blue.to_vec()))
+    }
+
+    /// Returns up to `k` colors representative of the pixels inside `rect`,
+    /// found by variance-minimizing median cut, as a list of `(color,
+    /// proportion)` tuples ordered from most to least common. Colors are
+    /// hexadecimal values, and proportions are in the range 0 to 1. If `rect`
+    /// is `None`, `bmp.bounds` is used instead. Pixels that are more than half
+    /// transparent are ignored.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if `k` is 0.
+    #[pyo3(signature = (k=5, rect=None))]
+    fn dominant_colors(
+        &self,
+        py: Python<'_>,
+        k: usize,
+        rect: Option<((f64, f64), (f64, f64))>,
+    ) -> PyResult<Vec<(u32, f64)>> {
+        if k == 0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Number of colors must be at least 1",
+            ));
+        }
+        let rect: Option<Rect> =
+            rect.map(|r| Rect::new(Point::new((r.0).0, (r.0).1), Size::new((r.1).0, (r.1).1)));
+        let colors = detach_reading(py, [self], || {
+            let image = rgba_image(&self.bitmap.image);
+            let region = Region::new(&image, (1, 1), self.pixel_rect(rect));
+            palette::dominant_colors(&image, region, k)
+        });
+        Ok(colors
+            .into_iter()
+            .map(|([r, g, b], proportion)| (rgb_to_hex(r, g, b), proportion))
+            .collect())
     }
 
     /// Attempts to find `needle` inside `rect` in `bmp` from the given
@@ -248,70 +756,391 @@ impl Bitmap {
     ///
     /// Tolerance is defined as a float in the range from 0 to 1, where 0 is an
     /// exact match and 1 matches anything.
-    #[pyo3(signature = (needle, tolerance=None, rect=None, start_point=None))]
+    ///
+    /// Needle pixels that are more than half transparent are ignored, so
+    /// icons with transparent backgrounds are found on any background. An
+    /// explicit `mask` bitmap of the same size as `needle` may be given
+    /// instead, in which case only needle pixels where the mask is white are
+    /// compared.
+    ///
+    /// `preprocess` is an optional list of filters applied in order to both
+    /// `bmp` and `needle` before searching, each given as a name or a `(name,
+    /// parameter)` tuple: "grayscale", ("threshold", level), "invert" or
+    /// ("blur", sigma). See the corresponding `Bitmap` methods for details.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the mask's size differs from the
+    ///       needle's, or if a preprocessing step is invalid.
+    #[pyo3(signature = (
+        needle,
+        tolerance=None,
+        rect=None,
+        start_point=None,
+        mask=None,
+        preprocess=None
+    ))]
+    #[allow(clippy::too_many_arguments)]
     fn find_bitmap(
         &self,
+        py: Python<'_>,
         needle: &Bitmap,
         tolerance: Option<f64>,
         rect: Option<((f64, f64), (f64, f64))>,
         start_point: Option<(f64, f64)>,
+        mask: Option<&Bitmap>,
+        preprocess: Option<Vec<Bound<'_, PyAny>>>,
     ) -> PyResult<Option<(f64, f64)>> {
+        if let Some(steps) = preprocess {
+            let (haystack, needle) = self.preprocessed(py, needle, &steps)?;
+            return haystack.find_bitmap(py, &needle, tolerance, rect, start_point, mask, None);
+        }
         let rect: Option<Rect> =
             rect.map(|r| Rect::new(Point::new((r.0).0, (r.0).1), Size::new((r.1).0, (r.1).1)));
         let start_point: Option<Point> = start_point.map(|p| Point::new(p.0, p.1));
-        if let Some(point) = self
-            .bitmap
-            .find_bitmap(&needle.bitmap, tolerance, rect, start_point)
-        {
-            Ok(Some((point.x, point.y)))
-        } else {
-            Ok(None)
-        }
+        detach_reading(py, [self, needle].into_iter().chain(mask), || {
+            let needle_mask = needle.mask(mask)?;
+            Ok(self.find_first(needle, needle_mask.as_deref(), tolerance, rect, start_point))
+        })
     }
 
     /// Returns list of all `(x, y)` coordinates inside `rect` in `bmp`
     /// matching `needle` from the given `start_point`. If `rect` is `None`,
     /// `bmp.bounds` is used instead. If `start_point` is `None`, the origin of
-    /// `rect` is used.
-    #[pyo3(signature = (needle, tolerance=None, rect=None, start_point=None))]
+    /// `rect` is used. Transparency, `mask` and `preprocess` are handled as
+    /// described for `find_bitmap`.
+    ///
+    /// With a tolerance above 0, a single on-screen instance of `needle` can
+    /// match at several neighbouring offsets. To collapse these into one
+    /// result per instance, pass `min_distance` (in points) to treat matches
+    /// closer than that as the same instance, and/or `max_overlap` to treat
+    /// matches whose intersection over union exceeds it (from 0 to 1) as the
+    /// same instance. The closest match of each instance is kept.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if `min_distance` is negative or
+    ///       `max_overlap` is not in the range 0 - 1.
+    #[pyo3(signature = (
+        needle,
+        tolerance=None,
+        rect=None,
+        start_point=None,
+        mask=None,
+        min_distance=None,
+        max_overlap=None,
+        preprocess=None
+    ))]
+    #[allow(clippy::too_many_arguments)]
     fn find_every_bitmap(
         &self,
+        py: Python<'_>,
         needle: &Bitmap,
         tolerance: Option<f64>,
         rect: Option<((f64, f64), (f64, f64))>,
         start_point: Option<(f64, f64)>,
+        mask: Option<&Bitmap>,
+        min_distance: Option<f64>,
+        max_overlap: Option<f64>,
+        preprocess: Option<Vec<Bound<'_, PyAny>>>,
     ) -> PyResult<Vec<(f64, f64)>> {
+        if let Some(steps) = preprocess {
+            let (haystack, needle) = self.preprocessed(py, needle, &steps)?;
+            return haystack.find_every_bitmap(
+                py,
+                &needle,
+                tolerance,
+                rect,
+                start_point,
+                mask,
+                min_distance,
+                max_overlap,
+                None,
+            );
+        }
         let rect: Option<Rect> =
             rect.map(|r| Rect::new(Point::new((r.0).0, (r.0).1), Size::new((r.1).0, (r.1).1)));
         let start_point: Option<Point> = start_point.map(|p| Point::new(p.0, p.1));
-        let points = self
-            .bitmap
-            .find_every_bitmap(&needle.bitmap, tolerance, rect, start_point)
-            .iter()
-            .map(|p| (p.x, p.y))
-            .collect();
-        Ok(points)
+        let suppression = self.suppression(min_distance, max_overlap)?;
+        detach_reading(py, [self, needle].into_iter().chain(mask), || {
+            let needle_mask = needle.mask(mask)?;
+            let points = self.find_every_needle(
+                needle,
+                needle_mask.as_deref(),
+                tolerance,
+                rect,
+                start_point,
+                None,
+            );
+            if let Some(suppression) = suppression {
+                Ok(self.suppress_overlapping(needle, needle_mask, points, suppression))
+            } else {
+                Ok(points)
+            }
+        })
     }
 
     /// Returns count of occurrences of `needle` in `bmp`. Functionally
     /// equivalent to:
     ///
-    /// `len(find_every_bitmap(color, tolerance, rect, start_point))`
-    #[pyo3(signature = (needle, tolerance=None, rect=None, start_point=None))]
+    /// `len(find_every_bitmap(color, tolerance, rect, start_point, mask,
+    /// min_distance, max_overlap, preprocess))`
+    ///
+    /// so that with `min_distance` or `max_overlap` given, distinct instances
+    /// are counted rather than matching offsets.
+    #[pyo3(signature = (
+        needle,
+        tolerance=None,
+        rect=None,
+        start_point=None,
+        mask=None,
+        min_distance=None,
+        max_overlap=None,
+        preprocess=None
+    ))]
+    #[allow(clippy::too_many_arguments)]
     fn count_of_bitmap(
         &self,
+        py: Python<'_>,
         needle: &Bitmap,
         tolerance: Option<f64>,
         rect: Option<((f64, f64), (f64, f64))>,
         start_point: Option<(f64, f64)>,
+        mask: Option<&Bitmap>,
+        min_distance: Option<f64>,
+        max_overlap: Option<f64>,
+        preprocess: Option<Vec<Bound<'_, PyAny>>>,
     ) -> PyResult<u64> {
+        if let Some(steps) = preprocess {
+            let (haystack, needle) = self.preprocessed(py, needle, &steps)?;
+            return haystack.count_of_bitmap(
+                py,
+                &needle,
+                tolerance,
+                rect,
+                start_point,
+                mask,
+                min_distance,
+                max_overlap,
+                None,
+            );
+        }
+        if min_distance.is_some() || max_overlap.is_some() {
+            let points = self.find_every_bitmap(
+                py,
+                needle,
+                tolerance,
+                rect,
+                start_point,
+                mask,
+                min_distance,
+                max_overlap,
+                None,
+            )?;
+            return Ok(points.len() as u64);
+        }
         let rect: Option<Rect> =
             rect.map(|r| Rect::new(Point::new((r.0).0, (r.0).1), Size::new((r.1).0, (r.1).1)));
         let start_point: Option<Point> = start_point.map(|p| Point::new(p.0, p.1));
-        let count = self
-            .bitmap
-            .count_of_bitmap(&needle.bitmap, tolerance, rect, start_point);
-        Ok(count)
+        detach_reading(py, [self, needle].into_iter().chain(mask), || {
+            let needle_mask = needle.mask(mask)?;
+            let points = self.find_every_needle(
+                needle,
+                needle_mask.as_deref(),
+                tolerance,
+                rect,
+                start_point,
+                None,
+            );
+            Ok(points.len() as u64)
+        })
+    }
+
+    /// Searches `rect` in `bmp` for the position most similar to `needle`,
+    /// giving each candidate a score with the given `method`. Returns `(x, y,
+    /// score)` for the best match, or `None` if its score is below
+    /// `threshold`. If `threshold` is `None`, the best match is returned no
+    /// matter how poor. If `rect` is `None`, `bmp.bounds` is used instead.
+    ///
+    /// `method` is one of:
+    ///     - "ncc": zero-normalized cross-correlation, scored from -1 to 1.
+    ///       Robust against anti-aliasing and uniform brightness changes. As
+    ///       uniform areas have no pattern to correlate, a uniform needle
+    ///       scores 1 against any uniform area and 0 elsewhere.
+    ///     - "sqdiff": one minus the root mean square difference between
+    ///       pixels, scored from 0 to 1.
+    ///
+    /// Transparency and `mask` are handled as described for `find_bitmap`.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the method is unknown, or if the mask's
+    ///       size differs from the needle's.
+    #[pyo3(signature = (needle, threshold=None, method="ncc", rect=None, mask=None))]
+    fn match_bitmap(
+        &self,
+        py: Python<'_>,
+        needle: &Bitmap,
+        threshold: Option<f64>,
+        method: &str,
+        rect: Option<((f64, f64), (f64, f64))>,
+        mask: Option<&Bitmap>,
+    ) -> PyResult<Option<(f64, f64, f64)>> {
+        let method = match_method_from_name(method)?;
+        let rect: Option<Rect> =
+            rect.map(|r| Rect::new(Point::new((r.0).0, (r.0).1), Size::new((r.1).0, (r.1).1)));
+        let needle_mask = needle.mask(mask)?;
+        let best = detach_reading(py, [self, needle], || {
+            let image = rgba_image(&self.bitmap.image);
+            let haystack = Haystack::new(&image);
+            let template = Template::new(&rgba_image(&needle.bitmap.image), needle_mask);
+            let region = Region::new(&image, needle.pixel_size(), self.pixel_rect(rect));
+            matching::find_best(&haystack, &template, region, method)
+        });
+        let result = best
+            .filter(|m| threshold.is_none_or(|t| m.score >= t))
+            .map(|m| self.scored_point(m));
+        Ok(result)
+    }
+
+    /// Returns list of all `(x, y, score)` tuples inside `rect` in `bmp`
+    /// whose similarity to `needle` is at least `threshold`, sorted from best
+    /// to worst. Scores and `method` are as described for `match_bitmap`.
+    /// `min_distance` and `max_overlap` collapse overlapping matches as
+    /// described for `find_every_bitmap`, keeping the best scoring one.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the method is unknown, if the mask's
+    ///       size differs from the needle's, or if `min_distance` or
+    ///       `max_overlap` are out of range.
+    #[pyo3(signature = (
+        needle,
+        threshold=0.9,
+        method="ncc",
+        rect=None,
+        mask=None,
+        min_distance=None,
+        max_overlap=None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn match_every_bitmap(
+        &self,
+        py: Python<'_>,
+        needle: &Bitmap,
+        threshold: f64,
+        method: &str,
+        rect: Option<((f64, f64), (f64, f64))>,
+        mask: Option<&Bitmap>,
+        min_distance: Option<f64>,
+        max_overlap: Option<f64>,
+    ) -> PyResult<Vec<(f64, f64, f64)>> {
+        let method = match_method_from_name(method)?;
+        let suppression = self.suppression(min_distance, max_overlap)?;
+        let rect: Option<Rect> =
+            rect.map(|r| Rect::new(Point::new((r.0).0, (r.0).1), Size::new((r.1).0, (r.1).1)));
+        let needle_mask = needle.mask(mask)?;
+        let matches = detach_reading(py, [self, needle], || {
+            let image = rgba_image(&self.bitmap.image);
+            let haystack = Haystack::new(&image);
+            let template = Template::new(&rgba_image(&needle.bitmap.image), needle_mask);
+            let region = Region::new(&image, needle.pixel_size(), self.pixel_rect(rect));
+            let mut matches = matching::find_all(&haystack, &template, region, method, threshold);
+            if let Some(suppression) = suppression {
+                let kept = matching::suppress_overlapping(&matches, needle.pixel_size(), suppression);
+                matches = kept.into_iter().map(|i| matches[i]).collect();
+            } else {
+                matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+            }
+            matches
+        });
+        Ok(matches.into_iter().map(|m| self.scored_point(m)).collect())
+    }
+
+    /// Like `match_bitmap`, but also tries `needle` resized by each factor in
+    /// `scales`, e.g. to find a needle captured on a standard display on a
+    /// HiDPI one. Returns `(x, y, score, scale)` for the best match, or `None`
+    /// if its score is below `threshold`.
+    ///
+    /// Scales are tried in order, and the search stops at the first scale
+    /// with a match scoring at least `threshold`. If `threshold` is `None`,
+    /// every scale is tried and the best match across all of them returned.
+    ///
+    /// If `scales` is `None`, factors from 0.5 to 2 times the ratio of
+    /// `bmp.scale` to `needle.scale` are tried, starting with the most likely
+    /// one, so that a needle and haystack captured at different screen scales
+    /// are compared at the same density.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the method is unknown, a scale is not
+    ///       positive, or the mask's size differs from the needle's.
+    #[pyo3(signature = (needle, scales=None, threshold=None, method="ncc", rect=None, mask=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn match_bitmap_scaled(
+        &self,
+        py: Python<'_>,
+        needle: &Bitmap,
+        scales: Option<Vec<f64>>,
+        threshold: Option<f64>,
+        method: &str,
+        rect: Option<((f64, f64), (f64, f64))>,
+        mask: Option<&Bitmap>,
+    ) -> PyResult<Option<(f64, f64, f64, f64)>> {
+        let method = match_method_from_name(method)?;
+        let rect: Option<Rect> =
+            rect.map(|r| Rect::new(Point::new((r.0).0, (r.0).1), Size::new((r.1).0, (r.1).1)));
+        let density = self.bitmap.scale / needle.bitmap.scale;
+        let scales = scales.unwrap_or_else(|| {
+            DEFAULT_MATCH_SCALES.iter().map(|x| x * density).collect()
+        });
+        if let Some(scale) = scales.iter().find(|&&x| x.is_nan() || x <= 0.0) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Scale must be positive, got {}",
+                scale
+            )));
+        }
+
+        let mask_image = needle.mask_image(mask)?;
+        let rect = self.pixel_rect(rect);
+        let best = detach_reading(py, [self, needle], || {
+            let image = rgba_image(&self.bitmap.image);
+            let haystack = Haystack::new(&image);
+            let needle_image = rgba_image(&needle.bitmap.image);
+            let mut best: Option<(Match, f64)> = None;
+            for &scale in &scales {
+                let width = (needle_image.width() as f64 * scale).round() as u32;
+                let height = (needle_image.height() as f64 * scale).round() as u32;
+                let fits = width <= image.width() && height <= image.height();
+                if width == 0 || height == 0 || !fits {
+                    continue;
+                }
+
+                let (scaled, scaled_mask) = if needle.pixel_size() == (width, height) {
+                    (needle_image.clone().into_owned(), mask_image.clone())
+                } else {
+                    let filter = imageops::FilterType::Triangle;
+                    (
+                        imageops::resize(&*needle_image, width, height, filter),
+                        mask_image.as_ref().map(|m| imageops::resize(m, width, height, filter)),
+                    )
+                };
+                let scaled_needle_mask = matching::needle_mask(&scaled, scaled_mask.as_ref());
+                let template = Template::new(&scaled, scaled_needle_mask);
+                let region = Region::new(&image, (width, height), rect);
+                let found = matching::find_best(&haystack, &template, region, method);
+                if let Some(m) = found.filter(|m| best.is_none_or(|(b, _)| m.score > b.score)) {
+                    best = Some((m, scale));
+                    if threshold.is_some_and(|t| m.score >= t) {
+                        break;
+                    }
+                }
+            }
+            best
+        });
+
+        let result = best
+            .filter(|(m, _)| threshold.is_none_or(|t| m.score >= t))
+            .map(|(m, scale)| {
+                let (x, y, score) = self.scored_point(m);
+                (x, y, score, scale)
+            });
+        Ok(result)
     }
 
     /// Returns new bitmap object created from a portion of another.
@@ -325,15 +1154,268 @@ impl Bitmap {
         );
         let bmp = self.bitmap.cropped(rect).map_err(FromImageError::from)?;
         Python::attach(|py| {
-            let result = Py::new(py, Bitmap { bitmap: bmp })?;
+            let result = Py::new(py, Bitmap::new(bmp))?;
             Ok(result)
         })
     }
 
+    /// Returns a new bitmap resized to `width` and `height` (in points, at the
+    /// receiver's scale). `filter` is one of "nearest", "triangle",
+    /// "catmullrom", "gaussian" or "lanczos3".
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the filter is unknown or the size is
+    ///       less than one pixel.
+    #[pyo3(signature = (width, height, filter="triangle"))]
+    fn resized(
+        &self,
+        py: Python<'_>,
+        width: f64,
+        height: f64,
+        filter: &str,
+    ) -> PyResult<Py<Bitmap>> {
+        let filter = filter_type_from_name(filter)?;
+        let size = Size::new(width, height).scaled(self.bitmap.scale);
+        self.resized_pixels(py, size.width.round(), size.height.round(), filter)
+    }
+
+    /// Returns a new bitmap with both dimensions multiplied by `factor`, at the
+    /// receiver's scale. `filter` is as in `resized`.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the filter is unknown or the size is
+    ///       less than one pixel.
+    #[pyo3(signature = (factor, filter="triangle"))]
+    fn scaled(&self, py: Python<'_>, factor: f64, filter: &str) -> PyResult<Py<Bitmap>> {
+        let filter = filter_type_from_name(filter)?;
+        let (width, height) = self.pixel_size();
+        let width = (width as f64 * factor).round();
+        let height = (height as f64 * factor).round();
+        self.resized_pixels(py, width, height, filter)
+    }
+
+    /// Returns a new bitmap rotated clockwise by the given number of degrees,
+    /// which must be a multiple of 90.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if `degrees` is not a multiple of 90.
+    fn rotated(&self, py: Python<'_>, degrees: i32) -> PyResult<Py<Bitmap>> {
+        let image = match degrees.rem_euclid(360) {
+            0 => self.bitmap.image.clone(),
+            90 => self.bitmap.image.rotate90(),
+            180 => self.bitmap.image.rotate180(),
+            270 => self.bitmap.image.rotate270(),
+            _ => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Rotation of {} degrees is not a multiple of 90",
+                    degrees
+                )));
+            }
+        };
+        self.transformed(py, image)
+    }
+
+    /// Returns a new bitmap mirrored from left to right.
+    fn flipped_horizontal(&self, py: Python<'_>) -> PyResult<Py<Bitmap>> {
+        self.transformed(py, self.bitmap.image.fliph())
+    }
+
+    /// Returns a new bitmap mirrored from top to bottom.
+    fn flipped_vertical(&self, py: Python<'_>) -> PyResult<Py<Bitmap>> {
+        self.transformed(py, self.bitmap.image.flipv())
+    }
+
+    /// Returns a new grayscale copy of the bitmap. Any alpha channel is kept.
+    fn grayscaled(&self, py: Python<'_>) -> PyResult<Py<Bitmap>> {
+        self.filtered(py, &[Filter::Grayscale])
+    }
+
+    /// Returns a new black and white copy of the bitmap, in which pixels with
+    /// a brightness of at least `level` (from 0 to 255) are white and the rest
+    /// are black. Any alpha channel is kept.
+    #[pyo3(signature = (level=128))]
+    fn thresholded(&self, py: Python<'_>, level: u8) -> PyResult<Py<Bitmap>> {
+        self.filtered(py, &[Filter::Threshold(level)])
+    }
+
+    /// Returns a new copy of the bitmap with its colors inverted, e.g. to
+    /// search a dark theme for a needle captured from a light one. Any alpha
+    /// channel is kept.
+    fn inverted(&self, py: Python<'_>) -> PyResult<Py<Bitmap>> {
+        self.filtered(py, &[Filter::Invert])
+    }
+
+    /// Returns a new copy of the bitmap smoothed with a Gaussian blur, where
+    /// `sigma` is the standard deviation in pixels.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if `sigma` is not positive.
+    #[pyo3(signature = (sigma=1.0))]
+    fn blurred(&self, py: Python<'_>, sigma: f64) -> PyResult<Py<Bitmap>> {
+        self.filtered(py, &[blur_filter(sigma)?])
+    }
+
+    /// Returns the smallest rect of the form `((x, y), (width, height))`
+    /// containing every pixel that differs from `background` by more than the
+    /// given tolerance, or `None` if there are no such pixels. Pixels that are
+    /// more than half transparent always count as background.
+    ///
+    /// If `background` is `None`, the most common color among the four corners
+    /// is used, preferring the top-left corner in case of a tie.
+    ///
+    /// Tolerance is defined as for `find_color`.
+    #[pyo3(signature = (background=None, tolerance=None))]
+    fn content_bounds(
+        &self,
+        py: Python<'_>,
+        background: Option<u32>,
+        tolerance: Option<f64>,
+    ) -> PyResult<Option<RectTuple>> {
+        let scale = self.bitmap.scale;
+        let bounds = detach_reading(py, [self], || {
+            self.content_pixel_rect(background, tolerance)
+        });
+        Ok(bounds.map(|(x, y, width, height)| {
+            (
+                (x as f64 / scale, y as f64 / scale),
+                (width as f64 / scale, height as f64 / scale),
+            )
+        }))
+    }
+
+    /// Returns a new bitmap cropped to `content_bounds(background,
+    /// tolerance)`.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the bitmap contains only background.
+    #[pyo3(signature = (background=None, tolerance=None))]
+    fn trimmed(
+        &self,
+        py: Python<'_>,
+        background: Option<u32>,
+        tolerance: Option<f64>,
+    ) -> PyResult<Py<Bitmap>> {
+        let image = detach_reading(py, [self], || {
+            self.content_pixel_rect(background, tolerance)
+                .map(|(x, y, width, height)| self.bitmap.image.crop_imm(x, y, width, height))
+        });
+        match image {
+            Some(image) => self.transformed(py, image),
+            None => Err(pyo3::exceptions::PyValueError::new_err(
+                "Bitmap contains only background",
+            )),
+        }
+    }
+
     /// Returns true if bitmap is equal to receiver with the given tolerance.
     #[pyo3(signature = (bitmap, tolerance=None))]
-    pub fn is_bitmap_equal(&self, bitmap: &Bitmap, tolerance: Option<f64>) -> PyResult<bool> {
-        Ok(self.bitmap.bitmap_eq(&bitmap.bitmap, tolerance))
+    pub fn is_bitmap_equal(
+        &self,
+        py: Python<'_>,
+        bitmap: &Bitmap,
+        tolerance: Option<f64>,
+    ) -> PyResult<bool> {
+        Ok(detach_reading(py, [self, bitmap], || {
+            self.bitmap.bitmap_eq(&bitmap.bitmap, tolerance)
+        }))
+    }
+
+    /// Returns true if bitmap is the same size as the receiver, and at most
+    /// `max_changed_pixels` of its pixels differ from the receiver's by more
+    /// than the given tolerance. Unlike `==`, this ignores the bitmaps'
+    /// scales.
+    #[pyo3(signature = (bitmap, tolerance=None, max_changed_pixels=0))]
+    fn is_close(
+        &self,
+        py: Python<'_>,
+        bitmap: &Bitmap,
+        tolerance: Option<f64>,
+        max_changed_pixels: usize,
+    ) -> PyResult<bool> {
+        let tolerance = tolerance.unwrap_or(0.0);
+        Ok(detach_reading(py, [self, bitmap], || {
+            self.is_close_to(bitmap, tolerance, max_changed_pixels)
+        }))
+    }
+
+    /// Returns a 64-bit perceptual hash of the bitmap. Unlike `hash(bmp)`,
+    /// similar looking bitmaps have hashes differing in only a few bits; use
+    /// `hash_distance` to compare them.
+    ///
+    /// `method` is one of "average" (compares pixels to the mean brightness),
+    /// "difference" (compares neighbouring pixels) or "dct" (compares low
+    /// frequencies, and is the most robust to rescaling and recompression).
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the method is unknown.
+    #[pyo3(signature = (method="average"))]
+    fn perceptual_hash(&self, py: Python<'_>, method: &str) -> PyResult<u64> {
+        let method = hash_method_from_name(method)?;
+        Ok(detach_reading(py, [self], || {
+            hashing::perceptual_hash(&self.bitmap.image, method)
+        }))
+    }
+
+    /// Compares the receiver with a bitmap of the same size, returning a
+    /// `BitmapDiff` describing the pixels that differ by more than the given
+    /// tolerance.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the bitmaps differ in size.
+    #[pyo3(signature = (bitmap, tolerance=None))]
+    fn diff(
+        &self,
+        py: Python<'_>,
+        bitmap: &Bitmap,
+        tolerance: Option<f64>,
+    ) -> PyResult<BitmapDiff> {
+        if bitmap.pixel_size() != self.pixel_size() {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Bitmap size {:?} differs from receiver size {:?}",
+                bitmap.pixel_size(),
+                self.pixel_size()
+            )));
+        }
+        let scale = self.bitmap.scale;
+        let tolerance = tolerance.unwrap_or(0.0);
+        let (image, changed, max_delta) = detach_reading(py, [self, bitmap], || {
+            let a = rgba_image(&self.bitmap.image);
+            let b = rgba_image(&bitmap.bitmap.image);
+            let mut image = RgbaImage::new(a.width(), a.height());
+            let mut changed = Vec::with_capacity(a.len() / 4);
+            let mut max_delta = 0;
+            for ((out, pa), pb) in image.pixels_mut().zip(a.pixels()).zip(b.pixels()) {
+                for c in 0..3 {
+                    max_delta = max_delta.max(pa[c].abs_diff(pb[c]));
+                }
+                let is_changed = !matching::colors_match(&pa.0, &pb.0, tolerance);
+                *out = if is_changed {
+                    Rgba([255, 0, 0, 255])
+                } else {
+                    let faded = 255 - (255 - pa.to_luma()[0]) / 4;
+                    Rgba([faded, faded, faded, 255])
+                };
+                changed.push(is_changed);
+            }
+            (image, changed, max_delta)
+        });
+        let regions = regions::connected_regions(&changed, image.width())
+            .into_iter()
+            .map(|r| {
+                (
+                    (r.min_x as f64 / scale, r.min_y as f64 / scale),
+                    (r.width() as f64 / scale, r.height() as f64 / scale),
+                )
+            })
+            .collect();
+        let bmp = autopilot::bitmap::Bitmap::new(DynamicImage::ImageRgba8(image), Some(scale));
+        Ok(BitmapDiff {
+            image: Py::new(py, Bitmap::new(bmp))?,
+            regions,
+            changed_pixels: changed.iter().filter(|&&x| x).count(),
+            total_pixels: changed.len(),
+            max_delta,
+        })
     }
 
     #[getter(scale)]
@@ -367,28 +1449,1141 @@ impl Bitmap {
     }
 }
 
-/// Returns a screengrab of the given portion of the main display, or the
-/// entire display if `rect` is `None`. The `rect` parameter is in the form of
-/// `((x, y), (width, height))`.
-///
-/// Exceptions:
-///     - `ValueError` is thrown if the rect is out of bounds.
-///     - `IOError` is thrown if the image failed to parse.
-#[pyfunction]
-#[pyo3(signature = (rect=None))]
-fn capture_screen(python: Python, rect: Option<((f64, f64), (f64, f64))>) -> PyResult<Py<Bitmap>> {
-    let result: ImageResult<autopilot::bitmap::Bitmap> = if let Some(rect) = rect {
-        let portion = Rect::new(
-            Point::new((rect.0).0, (rect.0).1),
-            Size::new((rect.1).0, (rect.1).1),
+impl Bitmap {
+    fn new(bitmap: autopilot::bitmap::Bitmap) -> Bitmap {
+        Bitmap {
+            bitmap,
+            writable_exports: AtomicUsize::new(0),
+        }
+    }
+
+    fn is_exported_writable(&self) -> bool {
+        self.writable_exports.load(Ordering::Relaxed) > 0
+    }
+
+    /// Fails if pixels may be written through a buffer export, since
+    /// modifying the bitmap would then race with them.
+    fn ensure_modifiable(&self) -> PyResult<()> {
+        if self.is_exported_writable() {
+            return Err(pyo3::exceptions::PyBufferError::new_err(
+                "Bitmap cannot be modified while a writable buffer is exported",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Converts a rect in points to a pixel rect `(x, y, width, height)`,
+    /// defaulting to the whole bitmap.
+    fn pixel_rect(&self, rect: Option<Rect>) -> (u32, u32, u32, u32) {
+        let rect = rect.unwrap_or_else(|| self.bitmap.bounds());
+        let origin = rect.origin.scaled(self.bitmap.scale).round();
+        let size = rect.size.scaled(self.bitmap.scale);
+        (
+            origin.x.max(0.0) as u32,
+            origin.y.max(0.0) as u32,
+            size.width.round().max(0.0) as u32,
+            size.height.round().max(0.0) as u32,
+        )
+    }
+
+    /// Returns the size of the bitmap in pixels.
+    fn pixel_size(&self) -> (u32, u32) {
+        (self.bitmap.image.width(), self.bitmap.image.height())
+    }
+
+    /// Returns which pixels of this needle take part in a search, or `None`
+    /// if all of them do. See `matching::needle_mask`.
+    fn mask(&self, mask: Option<&Bitmap>) -> PyResult<Option<Vec<bool>>> {
+        let mask = self.mask_image(mask)?;
+        if mask.is_none() && !self.bitmap.image.color().has_alpha() {
+            return Ok(None);
+        }
+        let needle = rgba_image(&self.bitmap.image);
+        Ok(matching::needle_mask(&needle, mask.as_ref()))
+    }
+
+    /// Returns the grayscale image of an explicit needle mask, after checking
+    /// it matches the size of this needle.
+    fn mask_image(&self, mask: Option<&Bitmap>) -> PyResult<Option<GrayImage>> {
+        match mask {
+            Some(mask) if mask.pixel_size() != self.pixel_size() => {
+                Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Mask size {:?} differs from needle size {:?}",
+                    mask.pixel_size(),
+                    self.pixel_size()
+                )))
+            }
+            Some(mask) => Ok(Some(mask.bitmap.image.to_luma8())),
+            None => Ok(None),
+        }
+    }
+
+    /// Implements `is_close`.
+    fn is_close_to(&self, bitmap: &Bitmap, tolerance: f64, max_changed_pixels: usize) -> bool {
+        if bitmap.pixel_size() != self.pixel_size() {
+            return false;
+        }
+        let a = rgba_image(&self.bitmap.image);
+        let b = rgba_image(&bitmap.bitmap.image);
+        a.pixels()
+            .zip(b.pixels())
+            .filter(|(pa, pb)| !matching::colors_match(&pa.0, &pb.0, tolerance))
+            .nth(max_changed_pixels)
+            .is_none()
+    }
+
+    /// Returns the first match of `needle`, given the mask returned by its
+    /// `mask`.
+    fn find_first(
+        &self,
+        needle: &Bitmap,
+        needle_mask: Option<&[bool]>,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Option<(f64, f64)> {
+        let points =
+            self.find_every_needle(needle, needle_mask, tolerance, rect, start_point, Some(1));
+        points.first().copied()
+    }
+
+    /// Searches for a needle, with "don't care" pixels if `mask` is given,
+    /// returning points in the same order as
+    /// `autopilot::bitmap::Bitmap::find_every_bitmap`. Needles of a higher
+    /// scale than the receiver are never found, as in `autopilot`.
+    fn find_every_needle(
+        &self,
+        needle: &Bitmap,
+        mask: Option<&[bool]>,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+        limit: Option<usize>,
+    ) -> Vec<(f64, f64)> {
+        if needle.bitmap.scale > self.bitmap.scale {
+            return Vec::new();
+        }
+        let haystack = rgba_image(&self.bitmap.image);
+        let needle_image = rgba_image(&needle.bitmap.image);
+        let region = Region::new(&haystack, needle.pixel_size(), self.pixel_rect(rect));
+        let start = self.pixel_start(start_point, region);
+        let tolerance = tolerance.unwrap_or(0.0);
+        let scale = self.bitmap.scale;
+        matching::find_every_needle(&haystack, &needle_image, mask, region, start, tolerance, limit)
+            .into_iter()
+            .map(|(x, y)| (x as f64 / scale, y as f64 / scale))
+            .collect()
+    }
+
+    /// Searches for a color, returning points in the same order as
+    /// `autopilot::bitmap::Bitmap::find_every_color`. Unlike `autopilot`,
+    /// this compares colors as described for `find_color` whatever the
+    /// tolerance.
+    fn find_every_color_in(
+        &self,
+        query: ColorQuery,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+        limit: Option<usize>,
+    ) -> Vec<(f64, f64)> {
+        let haystack = rgba_image(&self.bitmap.image);
+        let region = Region::new(&haystack, (1, 1), self.pixel_rect(rect));
+        let start = self.pixel_start(start_point, region);
+        let scale = self.bitmap.scale;
+        matching::find_every_color(&haystack, query, region, start, limit)
+            .into_iter()
+            .map(|(x, y)| (x as f64 / scale, y as f64 / scale))
+            .collect()
+    }
+
+    /// Converts a search's start point in points to pixels, defaulting to the
+    /// origin of `region`.
+    fn pixel_start(&self, start_point: Option<Point>, region: Region) -> (u32, u32) {
+        start_point
+            .map(|p| p.scaled(self.bitmap.scale).round())
+            .map(|p| (p.x.max(0.0) as u32, p.y.max(0.0) as u32))
+            .unwrap_or((region.x0, region.y0))
+    }
+
+    /// Validates the overlap criteria given in points, converting them to
+    /// pixels. Returns `None` if neither is given.
+    fn suppression(
+        &self,
+        min_distance: Option<f64>,
+        max_overlap: Option<f64>,
+    ) -> PyResult<Option<Suppression>> {
+        if let Some(min_distance) = min_distance.filter(|x| x.is_nan() || *x < 0.0) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Minimum distance must not be negative, got {}",
+                min_distance
+            )));
+        }
+        if let Some(max_overlap) = max_overlap.filter(|x| !(0.0..=1.0).contains(x)) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Maximum overlap must be in the range 0 - 1, got {}",
+                max_overlap
+            )));
+        }
+        if min_distance.is_none() && max_overlap.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Suppression {
+            min_distance: min_distance.map(|x| x * self.bitmap.scale),
+            max_overlap,
+        }))
+    }
+
+    /// Collapses overlapping tolerance matches of `needle`, given in points,
+    /// keeping the closest match of each instance. The scores only decide
+    /// which match survives; points are returned in their original order.
+    fn suppress_overlapping(
+        &self,
+        needle: &Bitmap,
+        needle_mask: Option<Vec<bool>>,
+        points: Vec<(f64, f64)>,
+        suppression: Suppression,
+    ) -> Vec<(f64, f64)> {
+        let image = rgba_image(&self.bitmap.image);
+        let haystack = Haystack::new(&image);
+        let template = Template::new(&rgba_image(&needle.bitmap.image), needle_mask);
+        let scale = self.bitmap.scale;
+        let matches: Vec<Match> = points
+            .iter()
+            .map(|&(x, y)| {
+                let x = (x * scale).round() as u32;
+                let y = (y * scale).round() as u32;
+                let score = matching::score_at(&haystack, &template, x, y, MatchMethod::Sqdiff);
+                Match { x, y, score }
+            })
+            .collect();
+        let mut kept = matching::suppress_overlapping(&matches, needle.pixel_size(), suppression);
+        kept.sort_unstable();
+        kept.into_iter().map(|i| points[i]).collect()
+    }
+
+    /// Converts a match in pixels to an `(x, y, score)` tuple in points.
+    fn scored_point(&self, m: Match) -> (f64, f64, f64) {
+        let scale = self.bitmap.scale;
+        (m.x as f64 / scale, m.y as f64 / scale, m.score)
+    }
+
+    /// Returns the pixel rect `(x, y, width, height)` bounding every pixel
+    /// not matching the background, as described for `content_bounds`.
+    fn content_pixel_rect(
+        &self,
+        background: Option<u32>,
+        tolerance: Option<f64>,
+    ) -> Option<(u32, u32, u32, u32)> {
+        let image = rgba_image(&self.bitmap.image);
+        let (width, height) = image.dimensions();
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let background = match background {
+            Some(hex) => {
+                let (r, g, b) = hex_to_rgb(hex);
+                [r, g, b]
+            }
+            None => {
+                let corners = [(0, 0), (width - 1, 0), (0, height - 1), (width - 1, height - 1)]
+                    .map(|(x, y)| image.get_pixel(x, y).0);
+                let occurrences = |color: &[u8; 4]| corners.iter().filter(|c| *c == color).count();
+                let corner = corners
+                    .iter()
+                    .rev()
+                    .max_by_key(|color| occurrences(color))
+                    .unwrap_or(&corners[0]);
+                [corner[0], corner[1], corner[2]]
+            }
+        };
+        let tolerance = tolerance.unwrap_or(0.0);
+        let mut bounds: Option<(u32, u32, u32, u32)> = None;
+        for (x, y, pixel) in image.enumerate_pixels() {
+            if pixel[3] < 128 || matching::colors_match(&pixel.0, &background, tolerance) {
+                continue;
+            }
+            bounds = Some(match bounds {
+                Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+                None => (x, y, x, y),
+            });
+        }
+        bounds.map(|(x0, y0, x1, y1)| (x0, y0, x1 - x0 + 1, y1 - y0 + 1))
+    }
+
+    /// Resizes the bitmap to the given number of pixels, releasing the GIL
+    /// while doing so.
+    fn resized_pixels(
+        &self,
+        py: Python<'_>,
+        width: f64,
+        height: f64,
+        filter: imageops::FilterType,
+    ) -> PyResult<Py<Bitmap>> {
+        let valid = |x: f64| (1.0..=u32::MAX as f64).contains(&x);
+        if !valid(width) || !valid(height) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Invalid bitmap size {}x{} pixels",
+                width, height
+            )));
+        }
+        let image = detach_reading(py, [self], || {
+            self.bitmap.image.resize_exact(width as u32, height as u32, filter)
+        });
+        self.transformed(py, image)
+    }
+
+    /// Applies filters to a copy of the bitmap, releasing the GIL while doing
+    /// so.
+    fn filtered(&self, py: Python<'_>, filters: &[Filter]) -> PyResult<Py<Bitmap>> {
+        let image = detach_reading(py, [self], || filters::apply(&self.bitmap.image, filters));
+        self.transformed(py, image)
+    }
+
+    /// Applies a search's preprocessing steps to copies of the receiver and
+    /// `needle`.
+    fn preprocessed(
+        &self,
+        py: Python<'_>,
+        needle: &Bitmap,
+        steps: &[Bound<'_, PyAny>],
+    ) -> PyResult<(Bitmap, Bitmap)> {
+        let filters = steps.iter().map(filter_from_step).collect::<PyResult<Vec<_>>>()?;
+        Ok(detach_reading(py, [self, needle], || {
+            let filtered = |bmp: &Bitmap| {
+                Bitmap::new(autopilot::bitmap::Bitmap::new(
+                    filters::apply(&bmp.bitmap.image, &filters),
+                    Some(bmp.bitmap.scale),
+                ))
+            };
+            (filtered(self), filtered(needle))
+        }))
+    }
+
+    /// Wraps a transformed copy of the bitmap's image, keeping its scale.
+    fn transformed(&self, py: Python<'_>, image: DynamicImage) -> PyResult<Py<Bitmap>> {
+        let bmp = autopilot::bitmap::Bitmap::new(image, Some(self.bitmap.scale));
+        Py::new(py, Bitmap::new(bmp))
+    }
+
+    fn encoded(&self, format: &str, options: &EncoderOptions) -> PyResult<Vec<u8>> {
+        let mut buffer = Cursor::new(Vec::new());
+        write_image(&self.bitmap.image, &mut buffer, format, options)?;
+        Ok(buffer.into_inner())
+    }
+}
+
+/// A rect in the form of `((x, y), (width, height))`, as passed to and from
+/// Python.
+type RectTuple = ((f64, f64), (f64, f64));
+
+/// A blob found by `Bitmap.find_color_regions`, in the form of `(bounds,
+/// centroid, count)`.
+type ColorRegion = (RectTuple, (f64, f64), u64);
+
+#[pyclass]
+/// The result of `Bitmap.diff`, describing where and by how much two bitmaps
+/// differ.
+struct BitmapDiff {
+    image: Py<Bitmap>,
+    regions: Vec<RectTuple>,
+    changed_pixels: usize,
+    total_pixels: usize,
+    max_delta: u8,
+}
+
+#[pymethods]
+impl BitmapDiff {
+    /// A bitmap the size of those compared, with changed pixels in red over a
+    /// faded grayscale copy of the receiver.
+    #[getter(image)]
+    fn image(&self, py: Python<'_>) -> PyResult<Py<Bitmap>> {
+        Ok(self.image.clone_ref(py))
+    }
+
+    /// Bounding boxes of each group of connected changed pixels, in the form
+    /// of `((x, y), (width, height))`.
+    #[getter(regions)]
+    fn regions(&self) -> PyResult<Vec<RectTuple>> {
+        Ok(self.regions.clone())
+    }
+
+    /// Number of pixels that differ by more than the tolerance.
+    #[getter(changed_pixels)]
+    fn changed_pixels(&self) -> PyResult<usize> {
+        Ok(self.changed_pixels)
+    }
+
+    /// Percentage of pixels that differ by more than the tolerance, from 0
+    /// to 100.
+    #[getter(changed_percentage)]
+    fn changed_percentage(&self) -> PyResult<f64> {
+        if self.total_pixels == 0 {
+            return Ok(0.0);
+        }
+        Ok(self.changed_pixels as f64 * 100.0 / self.total_pixels as f64)
+    }
+
+    /// Largest difference in any RGB channel between corresponding pixels,
+    /// regardless of the tolerance.
+    #[getter(max_delta)]
+    fn max_delta(&self) -> PyResult<u8> {
+        Ok(self.max_delta)
+    }
+}
+
+#[pyclass]
+/// Repeatedly captures a portion of the screen to tell when it starts or stops
+/// changing, e.g. when an animation begins or a page has finished loading.
+///
+/// `rect` and `display` select the portion of the screen as described for
+/// `capture_screen`, which is captured every `interval` seconds while
+/// waiting. Two captures count as the same if at most `max_changed_pixels`
+/// pixels differ by more than `tolerance`, as described for
+/// `Bitmap.is_close`.
+///
+/// The watcher remembers the last capture it made, starting with one made
+/// when it is created, and compares new captures against it.
+///
+/// Exceptions:
+///     - `ValueError` is thrown if the rect is out of bounds, if there is no
+///       display with the given id, or if `interval` isn't positive.
+///     - `IOError` is thrown if the image failed to parse.
+struct RegionWatcher {
+    portion: ScreenPortion,
+    interval: f64,
+    tolerance: f64,
+    max_changed_pixels: usize,
+    frame: Bitmap,
+}
+
+#[pymethods]
+impl RegionWatcher {
+    #[new]
+    #[pyo3(signature = (
+        rect=None,
+        display=None,
+        interval=0.1,
+        tolerance=None,
+        max_changed_pixels=0
+    ))]
+    fn new(
+        py: Python<'_>,
+        rect: Option<RectTuple>,
+        display: Option<u32>,
+        interval: f64,
+        tolerance: Option<f64>,
+        max_changed_pixels: usize,
+    ) -> PyResult<RegionWatcher> {
+        if !(interval > 0.0 && interval.is_finite()) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Interval must be a positive number of seconds, got {}",
+                interval
+            )));
+        }
+        let portion = ScreenPortion::new(rect, display)?;
+        let bmp = py
+            .detach(|| portion.capture())
+            .map_err(FromImageError::from)?;
+        Ok(RegionWatcher {
+            portion,
+            interval,
+            tolerance: tolerance.unwrap_or(0.0),
+            max_changed_pixels,
+            frame: Bitmap::new(bmp),
+        })
+    }
+
+    /// The last capture made by the watcher.
+    #[getter(frame)]
+    fn frame(&self, py: Python<'_>) -> PyResult<Py<Bitmap>> {
+        Py::new(py, Bitmap::new(self.frame.bitmap.clone()))
+    }
+
+    /// Waits until a capture differs from the last one made by the watcher,
+    /// and returns it.
+    ///
+    /// Exceptions:
+    ///     - `TimeoutError` is thrown if nothing has changed after `timeout`
+    ///       seconds.
+    ///     - `ValueError` is thrown if `timeout` is negative.
+    #[pyo3(signature = (timeout=10.0))]
+    fn wait_for_change(&mut self, py: Python<'_>, timeout: f64) -> PyResult<Py<Bitmap>> {
+        let previous = &self.frame;
+        let frame = internal::wait_until(py, timeout, self.interval, "region to change", || {
+            let frame = self.capture()?;
+            let changed =
+                !frame.is_close_to(previous, self.tolerance, self.max_changed_pixels);
+            Ok(changed.then_some(frame))
+        })?;
+        self.frame = frame;
+        self.frame(py)
+    }
+
+    /// Waits until the region has gone `duration` seconds without changing,
+    /// and returns the last capture. Changes made before this is called
+    /// don't count, so it always waits at least `duration` seconds.
+    ///
+    /// Exceptions:
+    ///     - `TimeoutError` is thrown if the region hasn't stopped changing
+    ///       after `timeout` seconds.
+    ///     - `ValueError` is thrown if `duration` or `timeout` is negative or
+    ///       too long.
+    #[pyo3(signature = (duration=0.5, timeout=10.0))]
+    fn wait_for_stable(
+        &mut self,
+        py: Python<'_>,
+        duration: f64,
+        timeout: f64,
+    ) -> PyResult<Py<Bitmap>> {
+        if !(duration >= 0.0 && duration.is_finite()) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Duration must be a non-negative number of seconds, got {}",
+                duration
+            )));
+        }
+        let duration = internal::duration_from_secs(duration, "Duration")?;
+        let mut previous = Bitmap::new(self.frame.bitmap.clone());
+        let mut stable_since = Instant::now();
+        let frame = internal::wait_until(py, timeout, self.interval, "region to settle", || {
+            let frame = self.capture()?;
+            let captured_at = Instant::now();
+            if !frame.is_close_to(&previous, self.tolerance, self.max_changed_pixels) {
+                stable_since = captured_at;
+            }
+            if captured_at.duration_since(stable_since) >= duration {
+                return Ok(Some(frame));
+            }
+            previous = frame;
+            Ok(None)
+        })?;
+        self.frame = frame;
+        self.frame(py)
+    }
+}
+
+impl RegionWatcher {
+    fn capture(&self) -> PyResult<Bitmap> {
+        let bmp = self.portion.capture().map_err(FromImageError::from)?;
+        Ok(Bitmap::new(bmp))
+    }
+}
+
+#[pyclass]
+/// Captures a portion of the screen `fps` times a second in a background
+/// thread, as an iterator of `(timestamp, bitmap)` tuples where `timestamp`
+/// is the number of seconds since the stream started. Capturing starts as
+/// soon as the stream is created, and stops when `stop` is called or the
+/// stream is used as a context manager and the `with` block exits.
+///
+/// `rect` and `display` select the portion of the screen as described for
+/// `capture_screen`. Up to `buffer_size` frames are kept until consumed; once
+/// the buffer is full, the oldest frame is dropped for each new one. Frames
+/// are also dropped if capturing can't keep up with `fps`.
+///
+/// If `directory` is given, frames are saved there as PNG files named
+/// `frame_<index>_<milliseconds>.png` instead of being buffered.
+///
+/// Iterating waits for the next frame with the GIL released, and ends once
+/// the stream has stopped and every buffered frame has been consumed.
+///
+/// Exceptions:
+///     - `ValueError` is thrown if the rect is out of bounds, if there is no
+///       display with the given id, or if `fps` isn't positive, is too small
+///       for its frame interval to be represented, or `buffer_size` is 0.
+///     - `IOError` is thrown if `directory` can't be created. Errors capturing
+///       or saving frames stop the stream, and are raised by the iterator
+///       after the frames before them.
+struct CaptureStream {
+    stream: stream::Stream,
+}
+
+#[pymethods]
+impl CaptureStream {
+    #[new]
+    #[pyo3(signature = (rect=None, fps=30.0, buffer_size=60, display=None, directory=None))]
+    fn new(
+        rect: Option<RectTuple>,
+        fps: f64,
+        buffer_size: usize,
+        display: Option<u32>,
+        directory: Option<PathBuf>,
+    ) -> PyResult<CaptureStream> {
+        if !(fps > 0.0 && fps.is_finite()) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Frame rate must be positive, got {}",
+                fps
+            )));
+        }
+        if buffer_size == 0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Buffer size must be at least 1",
+            ));
+        }
+        let period = Duration::try_from_secs_f64(1.0 / fps).map_err(|_| {
+            pyo3::exceptions::PyValueError::new_err(format!("Frame rate {:e} is too low", fps))
+        })?;
+        let portion = ScreenPortion::new(rect, display)?;
+        if let Some(directory) = &directory {
+            std::fs::create_dir_all(directory)?;
+        }
+        let stream =
+            stream::Stream::start(period, buffer_size, directory, move || portion.capture());
+        Ok(CaptureStream { stream })
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python<'_>) -> PyResult<Option<(f64, Py<Bitmap>)>> {
+        loop {
+            match py.detach(|| self.stream.next_frame(STREAM_POLL_INTERVAL)) {
+                stream::Next::Frame(frame) => return stream_frame(py, frame).map(Some),
+                stream::Next::Pending => py.check_signals()?,
+                stream::Next::Finished(Some(err)) => return Err(FromImageError::from(err).into()),
+                stream::Next::Finished(None) => return Ok(None),
+            }
+        }
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &self,
+        py: Python<'_>,
+        _exc_type: &Bound<'_, PyAny>,
+        _exc_value: &Bound<'_, PyAny>,
+        _traceback: &Bound<'_, PyAny>,
+    ) {
+        self.stop(py);
+    }
+
+    /// Stops capturing. Frames already buffered can still be consumed.
+    fn stop(&self, py: Python<'_>) {
+        py.detach(|| self.stream.stop());
+    }
+
+    /// Removes and returns every buffered frame as a list of
+    /// `(timestamp, bitmap)` tuples, without waiting for more.
+    fn drain(&self, py: Python<'_>) -> PyResult<Vec<(f64, Py<Bitmap>)>> {
+        let frames = self.stream.drain();
+        frames
+            .into_iter()
+            .map(|frame| stream_frame(py, frame))
+            .collect()
+    }
+
+    /// Whether frames are still being captured.
+    #[getter(is_running)]
+    fn is_running(&self) -> PyResult<bool> {
+        Ok(self.stream.is_running())
+    }
+
+    /// Number of frames captured so far, including any since dropped.
+    #[getter(captured_frames)]
+    fn captured_frames(&self) -> PyResult<u64> {
+        Ok(self.stream.captured())
+    }
+
+    /// Number of frames dropped so far, either because the buffer was full or
+    /// because capturing couldn't keep up with the frame rate.
+    #[getter(dropped_frames)]
+    fn dropped_frames(&self) -> PyResult<u64> {
+        Ok(self.stream.dropped())
+    }
+}
+
+/// How long `CaptureStream` waits for a frame between checking for signals
+/// such as `KeyboardInterrupt`.
+const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn stream_frame(py: Python<'_>, frame: stream::Frame) -> PyResult<(f64, Py<Bitmap>)> {
+    let bitmap = Py::new(py, Bitmap::new(frame.bitmap))?;
+    Ok((frame.timestamp, bitmap))
+}
+
+#[pyclass]
+/// Collects timestamped frames, e.g. of a UI test, and encodes them as an
+/// animated GIF or APNG that keeps their original timing.
+///
+/// Frames are added either one at a time with `append`, or from a
+/// `CaptureStream` with `record`. Every frame must be the same size.
+struct Recorder {
+    started: Instant,
+    frames: Vec<(f64, autopilot::bitmap::Bitmap)>,
+}
+
+#[pymethods]
+impl Recorder {
+    #[new]
+    fn new() -> Recorder {
+        Recorder {
+            started: Instant::now(),
+            frames: Vec::new(),
+        }
+    }
+
+    fn __len__(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Adds a frame shown from `timestamp`, in seconds, until the next
+    /// frame. If `timestamp` is `None`, the number of seconds since the
+    /// recorder was created is used.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if the bitmap's size differs from that of
+    ///       the first frame, or if `timestamp` is before the previous frame's.
+    #[pyo3(signature = (bitmap, timestamp=None))]
+    fn append(&mut self, bitmap: &Bitmap, timestamp: Option<f64>) -> PyResult<()> {
+        let timestamp = timestamp.unwrap_or_else(|| self.started.elapsed().as_secs_f64());
+        self.push_frame(timestamp, bitmap.bitmap.clone())
+    }
+
+    /// Adds frames from `stream` as they arrive, until the stream stops or
+    /// `duration` seconds have passed. Returns the number of frames added.
+    /// Their timestamps are converted to the recorder's clock, so frames from
+    /// streams and `append` may be mixed.
+    ///
+    /// Exceptions:
+    ///     - `ValueError` is thrown if `duration` is negative or too long, or
+    ///       if the frames' size differs from that of the first frame.
+    ///     - `IOError` is thrown if the stream failed to capture a frame.
+    #[pyo3(signature = (stream, duration=None))]
+    fn record(
+        &mut self,
+        py: Python<'_>,
+        stream: &CaptureStream,
+        duration: Option<f64>,
+    ) -> PyResult<usize> {
+        let deadline = match duration {
+            Some(duration) if !(duration >= 0.0 && duration.is_finite()) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Duration must be a non-negative number of seconds, got {}",
+                    duration
+                )));
+            }
+            Some(duration) => {
+                let duration = internal::duration_from_secs(duration, "Duration")?;
+                Instant::now().checked_add(duration)
+            }
+            None => None,
+        };
+        let started = stream.stream.started();
+        let offset = if started >= self.started {
+            started.duration_since(self.started).as_secs_f64()
+        } else {
+            -self.started.duration_since(started).as_secs_f64()
+        };
+
+        let mut added = 0;
+        loop {
+            let wait = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if !remaining.is_zero() => remaining.min(STREAM_POLL_INTERVAL),
+                    _ => break,
+                },
+                None => STREAM_POLL_INTERVAL,
+            };
+            match py.detach(|| stream.stream.next_frame(wait)) {
+                stream::Next::Frame(frame) => {
+                    self.push_frame(offset + frame.timestamp, frame.bitmap)?;
+                    added += 1;
+                }
+                stream::Next::Pending => py.check_signals()?,
+                stream::Next::Finished(Some(err)) => return Err(FromImageError::from(err).into()),
+                stream::Next::Finished(None) => break,
+            }
+        }
+        Ok(added)
+    }
+
+    /// Removes every frame.
+    fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /// Number of seconds between the first and last frames.
+    #[getter(duration)]
+    fn duration(&self) -> PyResult<f64> {
+        match (self.frames.first(), self.frames.last()) {
+            (Some((first, _)), Some((last, _))) => Ok(last - first),
+            _ => Ok(0.0),
+        }
+    }
+
+    /// Saves the frames as an animation to absolute path in the given
+    /// format, either "gif" or "apng". The format is determined from the
+    /// filename if possible, unless format is given, with ".png" files
+    /// saved as APNG. As with `Bitmap.save`, a binary file-like object may be
+    /// given instead of a path.
+    ///
+    /// Each frame is shown until the next one's timestamp, and the last for
+    /// as long as the frame before it. GIF delays are rounded to hundredths
+    /// of a second, and GIF colors are reduced to a palette of 256.
+    ///
+    /// If `dedup` is true, consecutive identical frames are merged into one
+    /// shown for their combined duration. Frames are resized by `scale`, e.g.
+    /// 0.5 halves their width and height. The animation plays `loops` times,
+    /// or forever if `loops` is 0.
+    ///
+    /// Exceptions:
+    ///     - `IOError` is thrown if the file could not be saved.
+    ///     - `ValueError` is thrown if there are no frames, the format is
+    ///       unsupported or `scale` isn't positive.
+    ///     - `TypeError` is thrown if `path` is neither a path nor writable.
+    #[pyo3(signature = (path, format=None, dedup=true, scale=1.0, loops=0))]
+    fn save(
+        &self,
+        py: Python<'_>,
+        path: &Bound<'_, PyAny>,
+        format: Option<&str>,
+        dedup: bool,
+        scale: f64,
+        loops: u16,
+    ) -> PyResult<()> {
+        if let Ok(file_path) = path.extract::<PathBuf>() {
+            let format = format
+                .or(file_path.extension().and_then(|x| x.to_str()))
+                .unwrap_or("");
+            let (format, options) = self.animation_options(format, dedup, scale, loops)?;
+            // Encoded up front so that an existing file is left untouched if
+            // encoding fails.
+            let mut bytes = Vec::new();
+            py.detach(|| self.encoded(&mut bytes, format, options))?;
+            std::fs::write(file_path, bytes)?;
+            Ok(())
+        } else if path.hasattr("write")? {
+            let name: Option<PathBuf> = path
+                .getattr("name")
+                .ok()
+                .and_then(|name| name.extract().ok());
+            let format = format
+                .or(name.as_ref().and_then(|x| x.extension()).and_then(|x| x.to_str()))
+                .unwrap_or("");
+            let bytes = self.encode(py, format, dedup, scale, loops)?;
+            path.call_method1("write", (bytes,))?;
+            Ok(())
+        } else {
+            Err(pyo3::exceptions::PyTypeError::new_err(
+                "Expected path or file-like object",
+            ))
+        }
+    }
+
+    /// Returns the frames encoded as an animation in the given format,
+    /// either "gif" or "apng", as `bytes`. The other arguments are as
+    /// described for `save`.
+    ///
+    /// Exceptions:
+    ///     - `IOError` is thrown if the animation could not be encoded.
+    ///     - `ValueError` is thrown if there are no frames, the format is
+    ///       unsupported or `scale` isn't positive.
+    #[pyo3(signature = (format, dedup=true, scale=1.0, loops=0))]
+    fn encode<'py>(
+        &self,
+        py: Python<'py>,
+        format: &str,
+        dedup: bool,
+        scale: f64,
+        loops: u16,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let (format, options) = self.animation_options(format, dedup, scale, loops)?;
+        let mut bytes = Vec::new();
+        py.detach(|| self.encoded(&mut bytes, format, options))?;
+        Ok(PyBytes::new(py, &bytes))
+    }
+}
+
+impl Recorder {
+    fn push_frame(&mut self, timestamp: f64, bitmap: autopilot::bitmap::Bitmap) -> PyResult<()> {
+        if let Some((previous, first)) = self.frames.last().zip(self.frames.first()) {
+            let size = (bitmap.image.width(), bitmap.image.height());
+            let first_size = (first.1.image.width(), first.1.image.height());
+            if size != first_size {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Frame size {:?} differs from first frame size {:?}",
+                    size, first_size
+                )));
+            }
+            if timestamp < previous.0 {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Timestamp {} is before previous frame's timestamp {}",
+                    timestamp, previous.0
+                )));
+            }
+        }
+        self.frames.push((timestamp, bitmap));
+        Ok(())
+    }
+
+    fn animation_options(
+        &self,
+        format: &str,
+        dedup: bool,
+        scale: f64,
+        loops: u16,
+    ) -> PyResult<(AnimationFormat, AnimationOptions)> {
+        if self.frames.is_empty() {
+            return Err(pyo3::exceptions::PyValueError::new_err("Recorder has no frames"));
+        }
+        let Some(format) = AnimationFormat::from_name(format) else {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Unsupported animation format '{}'",
+                format
+            )));
+        };
+        if !(scale > 0.0 && scale.is_finite()) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Scale must be positive, got {}",
+                scale
+            )));
+        }
+        Ok((format, AnimationOptions { dedup, scale, loops }))
+    }
+
+    fn encoded<W: Write>(
+        &self,
+        writer: W,
+        format: AnimationFormat,
+        options: AnimationOptions,
+    ) -> PyResult<()> {
+        let start = self.frames.first().map_or(0.0, |(timestamp, _)| *timestamp);
+        let frames: Vec<(f64, &DynamicImage)> = self
+            .frames
+            .iter()
+            .map(|(timestamp, bitmap)| (timestamp - start, &bitmap.image))
+            .collect();
+        animation::encode(writer, &frames, format, options).map_err(FromImageError::from)?;
+        Ok(())
+    }
+}
+
+/// Returns a screengrab of the given portion of the main display, or the
+/// entire display if `rect` is `None`. The `rect` parameter is in the form of
+/// `((x, y), (width, height))`.
+///
+/// If `display` is given, the display with that id is captured instead, and
+/// `rect` is relative to its top-left corner. The returned bitmap has the
+/// scale of that display, except on Windows, where captures always have the
+/// system scale.
+///
+/// Exceptions:
+///     - `ValueError` is thrown if the rect is out of bounds, or if there is
+///       no display with the given id.
+///     - `IOError` is thrown if the image failed to parse.
+#[pyfunction]
+#[pyo3(signature = (rect=None, display=None))]
+fn capture_screen(
+    python: Python,
+    rect: Option<((f64, f64), (f64, f64))>,
+    display: Option<u32>,
+) -> PyResult<Py<Bitmap>> {
+    let portion = ScreenPortion::new(rect, display)?;
+    let bmp = python
+        .detach(|| portion.capture())
+        .map_err(FromImageError::from)?;
+    let result = Py::new(python, Bitmap::new(bmp))?;
+    Ok(result)
+}
+
+/// Returns a screengrab of the whole virtual desktop, i.e. the smallest
+/// rectangle containing every display. Areas not covered by a display are
+/// black. The desktop's origin is that of the display furthest up and to the
+/// left, which is given by `screen.displays()`.
+///
+/// Exceptions:
+///     - `IOError` is thrown if the image failed to parse.
+#[pyfunction]
+fn capture_desktop(python: Python) -> PyResult<Py<Bitmap>> {
+    let bmp = python
+        .detach(|| {
+            let bounds = display::desktop_bounds(&display::displays());
+            ScreenPortion::Desktop(bounds).capture()
+        })
+        .map_err(FromImageError::from)?;
+    Py::new(python, Bitmap::new(bmp))
+}
+
+/// A portion of the screen, as given by the `rect` and `display` arguments of
+/// `capture_screen`.
+#[derive(Clone, Copy, Debug)]
+enum ScreenPortion {
+    /// A rect of the main display, or all of it, captured by `autopilot`.
+    Main(Option<Rect>),
+    /// A rect of the virtual desktop, in points.
+    Desktop(Rect),
+}
+
+impl ScreenPortion {
+    /// Resolves a rect relative to the display with the given id, or to the
+    /// main display if there is none. Only rects of other displays are checked
+    /// here; `autopilot` checks those of the main display when capturing.
+    fn new(rect: Option<RectTuple>, display: Option<u32>) -> PyResult<ScreenPortion> {
+        let Some(id) = display else {
+            return Ok(ScreenPortion::Main(rect.map(|r| {
+                Rect::new(Point::new((r.0).0, (r.0).1), Size::new((r.1).0, (r.1).1))
+            })));
+        };
+        let bounds = screen::display_with_id(id)?.bounds;
+        let rect = rect.unwrap_or(((0.0, 0.0), (bounds.size.width, bounds.size.height)));
+        let portion = Rect::new(
+            Point::new(bounds.origin.x + (rect.0).0, bounds.origin.y + (rect.0).1),
+            Size::new((rect.1).0, (rect.1).1),
         );
-        autopilot::bitmap::capture_screen_portion(portion)
-    } else {
-        autopilot::bitmap::capture_screen()
+        if !display::contains_rect(bounds, portion) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Rect out of bounds {:?}",
+                rect
+            )));
+        }
+        Ok(ScreenPortion::Desktop(portion))
+    }
+
+    fn capture(&self) -> ImageResult<autopilot::bitmap::Bitmap> {
+        match *self {
+            ScreenPortion::Main(Some(rect)) => autopilot::bitmap::capture_screen_portion(rect),
+            ScreenPortion::Main(None) => autopilot::bitmap::capture_screen(),
+            ScreenPortion::Desktop(rect) => display::capture(rect),
+        }
+    }
+}
+
+/// Waits for `needle` to appear on the main display, checking every
+/// `interval` seconds, and returns the `(x, y)` coordinates where it was
+/// found. If `rect` is given, only that portion of the screen is searched. It
+/// is in the form of `((x, y), (width, height))`.
+///
+/// Tolerance and transparency are handled as described for
+/// `Bitmap.find_bitmap`. The GIL is released while waiting.
+///
+/// Exceptions:
+///     - `TimeoutError` is thrown if `needle` hasn't appeared after `timeout`
+///       seconds.
+///     - `ValueError` is thrown if the rect is out of bounds, `timeout` is
+///       negative or `interval` isn't positive.
+#[pyfunction]
+#[pyo3(signature = (needle, timeout=10.0, interval=0.1, tolerance=None, rect=None))]
+fn wait_for(
+    py: Python<'_>,
+    needle: &Bitmap,
+    timeout: f64,
+    interval: f64,
+    tolerance: Option<f64>,
+    rect: Option<RectTuple>,
+) -> PyResult<(f64, f64)> {
+    let search = ScreenSearch::new(needle, tolerance, rect)?;
+    internal::wait_until(py, timeout, interval, "bitmap to appear", || search.find())
+}
+
+/// Waits for `needle` to no longer appear on the main display, e.g. for a
+/// spinner or loading dialog to go away, checking every `interval` seconds.
+/// Arguments are as described for `wait_for`.
+///
+/// Exceptions:
+///     - `TimeoutError` is thrown if `needle` is still present after `timeout`
+///       seconds.
+///     - `ValueError` is thrown if the rect is out of bounds, `timeout` is
+///       negative or `interval` isn't positive.
+#[pyfunction]
+#[pyo3(signature = (needle, timeout=10.0, interval=0.1, tolerance=None, rect=None))]
+fn wait_until_gone(
+    py: Python<'_>,
+    needle: &Bitmap,
+    timeout: f64,
+    interval: f64,
+    tolerance: Option<f64>,
+    rect: Option<RectTuple>,
+) -> PyResult<()> {
+    let search = ScreenSearch::new(needle, tolerance, rect)?;
+    internal::wait_until(py, timeout, interval, "bitmap to disappear", || {
+        Ok(search.find()?.is_none().then_some(()))
+    })
+}
+
+/// Converts a rect of the main display, checking it is in bounds.
+fn screen_portion(rect: Option<RectTuple>) -> PyResult<Option<Rect>> {
+    let Some(rect) = rect else {
+        return Ok(None);
     };
-    let bmp = result.map_err(FromImageError::from)?;
-    let result = Py::new(python, Bitmap { bitmap: bmp })?;
-    Ok(result)
+    let portion = Rect::new(
+        Point::new((rect.0).0, (rect.0).1),
+        Size::new((rect.1).0, (rect.1).1),
+    );
+    if !autopilot::screen::is_rect_visible(portion) {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Rect out of bounds {:?}",
+            rect
+        )));
+    }
+    Ok(Some(portion))
+}
+
+/// A repeated search for a needle on the main display, as made by `wait_for`
+/// and `wait_until_gone`.
+struct ScreenSearch {
+    /// A copy of the needle, since it is searched for without the GIL and its
+    /// pixels could otherwise be written through a buffer in the meantime.
+    needle: Bitmap,
+    needle_mask: Option<Vec<bool>>,
+    tolerance: Option<f64>,
+    portion: Option<Rect>,
+}
+
+impl ScreenSearch {
+    fn new(needle: &Bitmap, tolerance: Option<f64>, rect: Option<RectTuple>) -> PyResult<Self> {
+        Ok(ScreenSearch {
+            needle: Bitmap::new(needle.bitmap.clone()),
+            needle_mask: needle.mask(None)?,
+            tolerance,
+            portion: screen_portion(rect)?,
+        })
+    }
+
+    /// Captures the portion of the main display, or all of it, and returns
+    /// the screen coordinates where the needle is found in it.
+    fn find(&self) -> PyResult<Option<(f64, f64)>> {
+        let capture = match self.portion {
+            Some(portion) => autopilot::bitmap::capture_screen_portion(portion),
+            None => autopilot::bitmap::capture_screen(),
+        };
+        let haystack = Bitmap::new(capture.map_err(FromImageError::from)?);
+        let origin = self.portion.map_or(Point::new(0.0, 0.0), |portion| portion.origin);
+        let mask = self.needle_mask.as_deref();
+        let found = haystack.find_first(&self.needle, mask, self.tolerance, None, None);
+        Ok(found.map(|(x, y)| (origin.x + x, origin.y + y)))
+    }
+}
+
+/// Sets the number of threads that bitmap searches are split across, or uses
+/// every available core if `threads` is `None`. Defaults to 1, i.e. searches
+/// run serially. Serial and parallel searches share one implementation, so
+/// they return the same results in the same order.
+///
+/// Exceptions:
+///     - `ValueError` is thrown if `threads` is 0.
+#[pyfunction]
+#[pyo3(signature = (threads=None))]
+fn set_search_threads(threads: Option<usize>) -> PyResult<()> {
+    let threads = match threads {
+        Some(0) => {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Number of threads must be at least 1",
+            ));
+        }
+        Some(threads) => threads,
+        None => std::thread::available_parallelism().map_or(1, |x| x.get()),
+    };
+    matching::set_search_threads(threads);
+    Ok(())
+}
+
+/// Returns the number of threads that bitmap searches are split across.
+#[pyfunction]
+fn search_threads() -> PyResult<usize> {
+    Ok(matching::search_threads())
 }
 
 /// This module defines the class `Bitmap` for accessing bitmaps and searching
@@ -398,15 +2593,494 @@ fn capture_screen(python: Python, rect: Option<((f64, f64), (f64, f64))>) -> PyR
 #[pymodule]
 pub fn bitmap(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<Bitmap>()?;
+    m.add_class::<BitmapDiff>()?;
+    m.add_class::<RegionWatcher>()?;
+    m.add_class::<CaptureStream>()?;
+    m.add_class::<Recorder>()?;
+    m.add("TimeoutError", m.py().get_type::<TimeoutError>())?;
     m.add_wrapped(wrap_pyfunction!(capture_screen))?;
+    m.add_wrapped(wrap_pyfunction!(capture_desktop))?;
+    m.add_wrapped(wrap_pyfunction!(wait_for))?;
+    m.add_wrapped(wrap_pyfunction!(wait_until_gone))?;
+    m.add_wrapped(wrap_pyfunction!(set_search_threads))?;
+    m.add_wrapped(wrap_pyfunction!(search_threads))?;
+    m.add_wrapped(wrap_pyfunction!(hash_distance))?;
+    Ok(())
+}
+
+/// Needle scales tried by `match_bitmap_scaled` by default, relative to the
+/// difference in density between haystack and needle. The most likely scale
+/// comes first so that it wins ties, and is often the only one searched.
+const DEFAULT_MATCH_SCALES: [f64; 7] = [1.0, 0.75, 1.25, 0.5, 1.5, 1.75, 2.0];
+
+/// Largest width and height of an image in an ICO file, in pixels.
+const MAX_ICO_SIZE: u32 = 256;
+
+/// Returns the image as 8-bit RGBA, converting only if necessary.
+fn rgba_image(image: &DynamicImage) -> Cow<'_, RgbaImage> {
+    match image.as_rgba8() {
+        Some(rgba) => Cow::Borrowed(rgba),
+        None => Cow::Owned(image.to_rgba8()),
+    }
+}
+
+/// Runs `f`, which reads the pixels of `bitmaps`, without the GIL unless one of
+/// them has a writable buffer export, through which Python code could then
+/// change pixels while `f` reads them.
+fn detach_reading<'a, T, F>(
+    py: Python<'_>,
+    bitmaps: impl IntoIterator<Item = &'a Bitmap>,
+    f: F,
+) -> T
+where
+    F: Ungil + FnOnce() -> T,
+    T: Ungil,
+{
+    if bitmaps.into_iter().any(Bitmap::is_exported_writable) {
+        f()
+    } else {
+        py.detach(f)
+    }
+}
+
+/// Returns the image's pixel data as mutable bytes, the counterpart of
+/// `DynamicImage::as_bytes`.
+fn image_bytes_mut(image: &mut DynamicImage) -> &mut [u8] {
+    fn bytes<T: image::Primitive>(samples: &mut [T]) -> &mut [u8] {
+        let len = std::mem::size_of_val(samples);
+        // Samples are plain integers or floats, so any bytes are valid ones.
+        unsafe { std::slice::from_raw_parts_mut(samples.as_mut_ptr() as *mut u8, len) }
+    }
+    match image {
+        DynamicImage::ImageLuma8(image) => bytes(image),
+        DynamicImage::ImageLumaA8(image) => bytes(image),
+        DynamicImage::ImageRgb8(image) => bytes(image),
+        DynamicImage::ImageRgba8(image) => bytes(image),
+        DynamicImage::ImageLuma16(image) => bytes(image),
+        DynamicImage::ImageLumaA16(image) => bytes(image),
+        DynamicImage::ImageRgb16(image) => bytes(image),
+        DynamicImage::ImageRgba16(image) => bytes(image),
+        DynamicImage::ImageRgb32F(image) => bytes(image),
+        DynamicImage::ImageRgba32F(image) => bytes(image),
+        _ => unreachable!("Unsupported pixel type {:?}", image.color()),
+    }
+}
+
+fn match_method_from_name(name: &str) -> PyResult<MatchMethod> {
+    MatchMethod::from_name(name).ok_or_else(|| {
+        pyo3::exceptions::PyValueError::new_err(format!("Unknown match method {}", name))
+    })
+}
+
+fn color_query(
+    color: u32,
+    tolerance: Option<f64>,
+    alpha: Option<u8>,
+    match_transparent: bool,
+) -> ColorQuery {
+    let (r, g, b) = hex_to_rgb(color);
+    ColorQuery {
+        color: [r, g, b],
+        tolerance: tolerance.unwrap_or(0.0),
+        alpha,
+        match_transparent,
+    }
+}
+
+fn filter_type_from_name(name: &str) -> PyResult<imageops::FilterType> {
+    match name.to_lowercase().as_str() {
+        "nearest" => Ok(imageops::FilterType::Nearest),
+        "triangle" => Ok(imageops::FilterType::Triangle),
+        "catmullrom" => Ok(imageops::FilterType::CatmullRom),
+        "gaussian" => Ok(imageops::FilterType::Gaussian),
+        "lanczos3" => Ok(imageops::FilterType::Lanczos3),
+        _ => Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Unknown filter {}",
+            name
+        ))),
+    }
+}
+
+/// Parses a preprocessing step, given either as a filter name or a `(name,
+/// parameter)` tuple.
+fn filter_from_step(step: &Bound<'_, PyAny>) -> PyResult<Filter> {
+    let (name, parameter): (String, Option<f64>) = match step.extract::<String>() {
+        Ok(name) => (name, None),
+        Err(_) => step.extract()?,
+    };
+    match (name.to_lowercase().as_str(), parameter) {
+        ("grayscale", None) => Ok(Filter::Grayscale),
+        ("invert", None) => Ok(Filter::Invert),
+        ("threshold", None) => Ok(Filter::Threshold(128)),
+        ("threshold", Some(level)) if (0.0..=255.0).contains(&level) => {
+            Ok(Filter::Threshold(level.round() as u8))
+        }
+        ("threshold", Some(level)) => Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Threshold level {} is not in the range 0 - 255",
+            level
+        ))),
+        ("blur", sigma) => blur_filter(sigma.unwrap_or(1.0)),
+        ("grayscale" | "invert", Some(_)) => Err(pyo3::exceptions::PyValueError::new_err(
+            format!("Preprocessing step {} takes no parameter", name),
+        )),
+        _ => Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Unknown preprocessing step {}",
+            name
+        ))),
+    }
+}
+
+fn blur_filter(sigma: f64) -> PyResult<Filter> {
+    if !(sigma.is_finite() && sigma > 0.0) {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Blur sigma {} is not positive",
+            sigma
+        )));
+    }
+    Ok(Filter::Blur(sigma as f32))
+}
+
+/// Returns the number of bits that differ between two perceptual hashes
+/// returned by `Bitmap.perceptual_hash`, from 0 (identical) to 64.
+#[pyfunction]
+fn hash_distance(a: u64, b: u64) -> PyResult<u32> {
+    Ok(hashing::hamming_distance(a, b))
+}
+
+fn hash_method_from_name(name: &str) -> PyResult<HashMethod> {
+    HashMethod::from_name(name).ok_or_else(|| {
+        pyo3::exceptions::PyValueError::new_err(format!("Unknown hash method {}", name))
+    })
+}
+
+/// Shape, strides and element type of a bitmap's pixel data, as exposed to
+/// the buffer protocol and NumPy.
+struct PixelLayout {
+    format: &'static CStr,
+    typestr: &'static str,
+    itemsize: isize,
+    ndim: usize,
+    height: isize,
+    width: isize,
+    channels: isize,
+}
+
+impl PixelLayout {
+    fn new(image: &DynamicImage) -> PixelLayout {
+        let color = image.color();
+        let (format, typestr, itemsize) = match color {
+            ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => {
+                (c"H", if cfg!(target_endian = "little") { "<u2" } else { ">u2" }, 2)
+            }
+            ColorType::Rgb32F | ColorType::Rgba32F => {
+                (c"f", if cfg!(target_endian = "little") { "<f4" } else { ">f4" }, 4)
+            }
+            _ => (c"B", "|u1", 1),
+        };
+        let channels = color.channel_count() as isize;
+        PixelLayout {
+            format,
+            typestr,
+            itemsize,
+            ndim: if channels == 1 { 2 } else { 3 },
+            height: image.height() as isize,
+            width: image.width() as isize,
+            channels,
+        }
+    }
+
+    /// Describes the same pixels as unsigned bytes, splitting each sample
+    /// into its bytes along the last dimension.
+    fn into_bytes(self) -> PixelLayout {
+        let channels = self.channels * self.itemsize;
+        PixelLayout {
+            format: c"B",
+            typestr: "|u1",
+            itemsize: 1,
+            ndim: if channels == 1 { 2 } else { 3 },
+            channels,
+            ..self
+        }
+    }
+
+    /// Returns the shape followed by the strides (in bytes), each padded to
+    /// three dimensions.
+    fn dimensions(&self) -> [isize; 6] {
+        let pixel_stride = self.channels * self.itemsize;
+        if self.ndim == 2 {
+            [self.height, self.width, 0, self.width * pixel_stride, pixel_stride, 0]
+        } else {
+            [
+                self.height,
+                self.width,
+                self.channels,
+                self.width * pixel_stride,
+                pixel_stride,
+                self.itemsize,
+            ]
+        }
+    }
+}
+
+/// Channel layout of raw pixel data passed in from Python.
+#[derive(Clone, Copy, PartialEq)]
+enum PixelMode {
+    Luma,
+    LumaA,
+    Rgb,
+    Rgba,
+    Bgr,
+    Bgra,
+}
+
+impl PixelMode {
+    fn from_name(name: &str) -> PyResult<PixelMode> {
+        match name.to_uppercase().as_str() {
+            "L" => Ok(PixelMode::Luma),
+            "LA" => Ok(PixelMode::LumaA),
+            "RGB" => Ok(PixelMode::Rgb),
+            "RGBA" => Ok(PixelMode::Rgba),
+            "BGR" => Ok(PixelMode::Bgr),
+            "BGRA" => Ok(PixelMode::Bgra),
+            _ => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Unknown pixel mode {}",
+                name
+            ))),
+        }
+    }
+
+    fn from_channels(channels: usize) -> PyResult<PixelMode> {
+        match channels {
+            1 => Ok(PixelMode::Luma),
+            2 => Ok(PixelMode::LumaA),
+            3 => Ok(PixelMode::Rgb),
+            4 => Ok(PixelMode::Rgba),
+            _ => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Unsupported number of channels {}",
+                channels
+            ))),
+        }
+    }
+
+    fn channels(self) -> usize {
+        match self {
+            PixelMode::Luma => 1,
+            PixelMode::LumaA => 2,
+            PixelMode::Rgb | PixelMode::Bgr => 3,
+            PixelMode::Rgba | PixelMode::Bgra => 4,
+        }
+    }
+}
+
+/// Packs raw pixel rows of the given mode and stride into an image, swapping
+/// BGR(A) data into RGB(A) order.
+fn image_from_raw(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+    mode: PixelMode,
+    stride: Option<usize>,
+) -> PyResult<DynamicImage> {
+    let too_large = || {
+        pyo3::exceptions::PyValueError::new_err(format!(
+            "Bitmap of {}x{} pixels is too large",
+            width, height
+        ))
+    };
+    let row_len = (width as usize).checked_mul(mode.channels()).ok_or_else(too_large)?;
+    let stride = stride.unwrap_or(row_len);
+    if stride < row_len {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Stride {} is smaller than row length {}",
+            stride, row_len
+        )));
+    }
+    let required = match height {
+        0 => 0,
+        _ => stride
+            .checked_mul(height as usize - 1)
+            .and_then(|x| x.checked_add(row_len))
+            .ok_or_else(too_large)?,
+    };
+    if bytes.len() < required {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Expected at least {} bytes of pixel data, got {}",
+            required,
+            bytes.len()
+        )));
+    }
+
+    let mut packed = Vec::with_capacity(row_len * height as usize);
+    for row in 0..height as usize {
+        packed.extend_from_slice(&bytes[row * stride..row * stride + row_len]);
+    }
+    if mode == PixelMode::Bgr || mode == PixelMode::Bgra {
+        for pixel in packed.chunks_exact_mut(mode.channels()) {
+            pixel.swap(0, 2);
+        }
+    }
+
+    // The buffer length has been checked above, so construction can't fail.
+    let image = match mode {
+        PixelMode::Luma => {
+            ImageBuffer::from_raw(width, height, packed).map(DynamicImage::ImageLuma8)
+        }
+        PixelMode::LumaA => {
+            ImageBuffer::from_raw(width, height, packed).map(DynamicImage::ImageLumaA8)
+        }
+        PixelMode::Rgb | PixelMode::Bgr => {
+            ImageBuffer::from_raw(width, height, packed).map(DynamicImage::ImageRgb8)
+        }
+        PixelMode::Rgba | PixelMode::Bgra => {
+            ImageBuffer::from_raw(width, height, packed).map(DynamicImage::ImageRgba8)
+        }
+    };
+    Ok(image.expect("pixel data has been validated"))
+}
+
+/// Format specific settings used when encoding a bitmap.
+struct EncoderOptions {
+    jpeg_quality: Option<u8>,
+    png_compression: Option<CompressionType>,
+}
+
+impl EncoderOptions {
+    fn new(quality: Option<i64>, compression: Option<&str>) -> PyResult<EncoderOptions> {
+        if let Some(quality) = quality.filter(|x| !(1..=100).contains(x)) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Quality must be in the range 1 - 100, got {}",
+                quality
+            )));
+        }
+        let jpeg_quality = quality.map(|x| x as u8);
+        let png_compression = match compression.map(|x| x.to_lowercase()).as_deref() {
+            None => None,
+            Some("fast") => Some(CompressionType::Fast),
+            Some("default") => Some(CompressionType::Default),
+            Some("best") => Some(CompressionType::Best),
+            Some(other) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Unknown compression level {}",
+                    other
+                )));
+            }
+        };
+        Ok(EncoderOptions {
+            jpeg_quality,
+            png_compression,
+        })
+    }
+}
+
+/// Encodes the image in the format given by a file extension such as "png",
+/// converting it first to a color type the encoder accepts where necessary
+/// (e.g. JPEG has no alpha channel).
+fn write_image<W: Write + Seek>(
+    image: &DynamicImage,
+    writer: &mut W,
+    extension: &str,
+    options: &EncoderOptions,
+) -> PyResult<()> {
+    let format = ImageFormat::try_from(image_output_format_from_extension(extension))?;
+    if format == ImageFormat::Ico && (image.width() > MAX_ICO_SIZE || image.height() > MAX_ICO_SIZE) {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "ICO images can be at most {}x{} pixels, got {}x{}",
+            MAX_ICO_SIZE,
+            MAX_ICO_SIZE,
+            image.width(),
+            image.height()
+        )));
+    }
+    if format == ImageFormat::Pnm {
+        let subtype = pnm_subtype(extension, image.color());
+        let image = pnm_image(image, subtype);
+        let encoder = PnmEncoder::new(writer).with_subtype(subtype);
+        image.write_with_encoder(encoder).map_err(FromImageError::from)?;
+        return Ok(());
+    }
+
+    let converted: DynamicImage;
+    let image = match (format, image.color()) {
+        (ImageFormat::Jpeg, ColorType::L8 | ColorType::Rgb8) => image,
+        (ImageFormat::Jpeg, ColorType::L16 | ColorType::La8 | ColorType::La16) => {
+            converted = DynamicImage::ImageLuma8(image.to_luma8());
+            &converted
+        }
+        (ImageFormat::Jpeg, _) => {
+            converted = DynamicImage::ImageRgb8(image.to_rgb8());
+            &converted
+        }
+        (ImageFormat::Qoi | ImageFormat::WebP, ColorType::Rgb8 | ColorType::Rgba8) => image,
+        (ImageFormat::Qoi | ImageFormat::WebP, _) => {
+            converted = DynamicImage::ImageRgba8(image.to_rgba8());
+            &converted
+        }
+        _ => image,
+    };
+
+    let result = match (format, options.jpeg_quality, options.png_compression) {
+        (ImageFormat::Jpeg, Some(quality), _) => {
+            image.write_with_encoder(JpegEncoder::new_with_quality(writer, quality))
+        }
+        (ImageFormat::Png, _, Some(compression)) => {
+            image.write_with_encoder(PngEncoder::new_with_quality(
+                writer,
+                compression,
+                PngFilterType::Adaptive,
+            ))
+        }
+        _ => image.write_to(writer, format),
+    };
+    result.map_err(FromImageError::from)?;
     Ok(())
 }
 
+/// Returns the PNM variant a file extension stands for. "pnm" is written as a
+/// graymap or pixmap depending on whether the image has color.
+fn pnm_subtype(extension: &str, color: ColorType) -> PnmSubtype {
+    match extension.to_lowercase().as_str() {
+        "pam" => PnmSubtype::ArbitraryMap,
+        "pbm" => PnmSubtype::Bitmap(SampleEncoding::Binary),
+        "pgm" => PnmSubtype::Graymap(SampleEncoding::Binary),
+        "pnm" if !color.has_color() => PnmSubtype::Graymap(SampleEncoding::Binary),
+        _ => PnmSubtype::Pixmap(SampleEncoding::Binary),
+    }
+}
+
+/// Converts the image to the only color type the PNM variant holds, dropping
+/// any alpha channel except in PAM files. Bitmaps are black where the image
+/// is darker than mid-gray.
+fn pnm_image(image: &DynamicImage, subtype: PnmSubtype) -> Cow<'_, DynamicImage> {
+    match (subtype, image.color()) {
+        (PnmSubtype::ArbitraryMap, ColorType::Rgb32F | ColorType::Rgba32F) => {
+            Cow::Owned(DynamicImage::ImageRgba16(image.to_rgba16()))
+        }
+        (PnmSubtype::ArbitraryMap, _) => Cow::Borrowed(image),
+        (PnmSubtype::Bitmap(_), _) => {
+            let mut luma = image.to_luma8();
+            for pixel in luma.pixels_mut() {
+                pixel[0] = if pixel[0] < 128 { 0 } else { 255 };
+            }
+            Cow::Owned(DynamicImage::ImageLuma8(luma))
+        }
+        (PnmSubtype::Graymap(_), ColorType::L8) => Cow::Borrowed(image),
+        (PnmSubtype::Graymap(_), _) => Cow::Owned(DynamicImage::ImageLuma8(image.to_luma8())),
+        (PnmSubtype::Pixmap(_), ColorType::Rgb8) => Cow::Borrowed(image),
+        (PnmSubtype::Pixmap(_), _) => Cow::Owned(DynamicImage::ImageRgb8(image.to_rgb8())),
+    }
+}
+
 enum AutoPyImageFormat {
     BMP,
     GIF,
+    ICO,
     JPEG,
     PNG,
+    PNM,
+    QOI,
+    TGA,
+    TIFF,
+    WEBP,
     Unsupported,
 }
 
@@ -418,8 +3092,14 @@ impl TryFrom<AutoPyImageFormat> for ImageFormat {
         match format {
             AutoPyImageFormat::BMP => Ok(Bmp),
             AutoPyImageFormat::GIF => Ok(Gif),
+            AutoPyImageFormat::ICO => Ok(Ico),
             AutoPyImageFormat::JPEG => Ok(Jpeg),
             AutoPyImageFormat::PNG => Ok(Png),
+            AutoPyImageFormat::PNM => Ok(Pnm),
+            AutoPyImageFormat::QOI => Ok(Qoi),
+            AutoPyImageFormat::TGA => Ok(Tga),
+            AutoPyImageFormat::TIFF => Ok(Tiff),
+            AutoPyImageFormat::WEBP => Ok(WebP),
             AutoPyImageFormat::Unsupported => {
                 Err(pyo3::exceptions::PyValueError::new_err("This image format is unsupported by AutoPy"))
             }
@@ -433,8 +3113,14 @@ impl From<ImageFormat> for AutoPyImageFormat {
         match format {
             Bmp => AutoPyImageFormat::BMP,
             Gif => AutoPyImageFormat::GIF,
+            Ico => AutoPyImageFormat::ICO,
             Jpeg => AutoPyImageFormat::JPEG,
             Png => AutoPyImageFormat::PNG,
+            Pnm => AutoPyImageFormat::PNM,
+            Qoi => AutoPyImageFormat::QOI,
+            Tga => AutoPyImageFormat::TGA,
+            Tiff => AutoPyImageFormat::TIFF,
+            WebP => AutoPyImageFormat::WEBP,
             _ => AutoPyImageFormat::Unsupported,
         }
     }
@@ -445,8 +3131,61 @@ fn image_output_format_from_extension(extension: &str) -> AutoPyImageFormat {
     match extension {
         "bmp" => AutoPyImageFormat::BMP,
         "gif" => AutoPyImageFormat::GIF,
-        "jpeg" => AutoPyImageFormat::JPEG,
+        "ico" => AutoPyImageFormat::ICO,
+        "jpeg" | "jpg" | "jpe" => AutoPyImageFormat::JPEG,
         "png" => AutoPyImageFormat::PNG,
+        "pnm" | "pbm" | "pgm" | "ppm" | "pam" => AutoPyImageFormat::PNM,
+        "qoi" => AutoPyImageFormat::QOI,
+        "tga" => AutoPyImageFormat::TGA,
+        "tiff" | "tif" => AutoPyImageFormat::TIFF,
+        "webp" => AutoPyImageFormat::WEBP,
         _ => AutoPyImageFormat::Unsupported,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::exceptions::{PyRuntimeError, PyValueError};
+
+    fn bitmap(py: Python<'_>, width: u32, height: u32) -> Bound<'_, Bitmap> {
+        let image = DynamicImage::new_rgb8(width, height);
+        let bmp = autopilot::bitmap::Bitmap::new(image, None);
+        Bound::new(py, Bitmap::new(bmp)).unwrap()
+    }
+
+    #[test]
+    fn paste_fails_while_borrowed() {
+        Python::initialize();
+        Python::attach(|py| {
+            let bmp = bitmap(py, 4, 4);
+            let source = bitmap(py, 2, 2);
+            let reader = bmp.borrow();
+            let err = Bitmap::paste(&bmp, &source, (1.0, 1.0)).unwrap_err();
+            assert!(err.is_instance_of::<PyRuntimeError>(py));
+            drop(reader);
+            assert!(Bitmap::paste(&bmp, &source, (1.0, 1.0)).is_ok());
+        });
+    }
+
+    fn assert_value_error(result: PyResult<DynamicImage>) {
+        Python::attach(|py| assert!(result.unwrap_err().is_instance_of::<PyValueError>(py)));
+    }
+
+    #[test]
+    fn raw_pixels_are_validated() {
+        Python::initialize();
+        let bytes = [0u8; 24];
+        assert!(image_from_raw(&bytes, 2, 3, PixelMode::Rgba, None).is_ok());
+        assert!(image_from_raw(&bytes, 2, 2, PixelMode::Rgb, Some(9)).is_ok());
+        // Short buffers, including with a stride padding all but the last row.
+        assert_value_error(image_from_raw(&bytes, 3, 3, PixelMode::Rgba, None));
+        assert_value_error(image_from_raw(&bytes, 2, 3, PixelMode::Rgb, Some(10)));
+        // Strides shorter than a row.
+        assert_value_error(image_from_raw(&bytes, 2, 2, PixelMode::Rgb, Some(5)));
+        // Sizes whose byte count overflows.
+        let (huge, stride) = (u32::MAX, Some(usize::MAX));
+        assert_value_error(image_from_raw(&bytes, huge, huge, PixelMode::Rgba, None));
+        assert_value_error(image_from_raw(&bytes, 1, 2, PixelMode::Luma, stride));
+    }
+}
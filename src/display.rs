@@ -0,0 +1,405 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Enumeration and capture of the individual displays making up the virtual
+//! desktop. `autopilot` only knows about the main display, so this talks to
+//! the platform directly.
+
+use autopilot::geometry::{Point, Rect, Size};
+use image::error::{LimitError, LimitErrorKind};
+use image::{ImageError, ImageResult};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Display {
+    pub id: u32,
+    /// Bounds of the display within the virtual desktop, in points.
+    pub bounds: Rect,
+    pub scale: f64,
+    pub is_primary: bool,
+}
+
+/// Returns every active display, primary first. Falls back to the main
+/// display as reported by `autopilot` if the platform reports none.
+pub fn displays() -> Vec<Display> {
+    let mut displays = platform::displays();
+    if displays.is_empty() {
+        displays.push(Display {
+            id: 0,
+            bounds: Rect::new(Point::new(0.0, 0.0), autopilot::screen::size()),
+            scale: autopilot::screen::scale(),
+            is_primary: true,
+        });
+    }
+    displays.sort_by_key(|display| !display.is_primary);
+    displays
+}
+
+/// Returns the display with the given id, if it is active.
+pub fn display(id: u32) -> Option<Display> {
+    displays().into_iter().find(|display| display.id == id)
+}
+
+/// Returns the smallest rect containing every display.
+pub fn desktop_bounds(displays: &[Display]) -> Rect {
+    let min_x = displays.iter().map(|d| d.bounds.origin.x).fold(f64::INFINITY, f64::min);
+    let min_y = displays.iter().map(|d| d.bounds.origin.y).fold(f64::INFINITY, f64::min);
+    let max_x = displays.iter().map(|d| max_x(d.bounds)).fold(f64::NEG_INFINITY, f64::max);
+    let max_y = displays.iter().map(|d| max_y(d.bounds)).fold(f64::NEG_INFINITY, f64::max);
+    Rect::new(
+        Point::new(min_x, min_y),
+        Size::new(max_x - min_x, max_y - min_y),
+    )
+}
+
+/// Returns `true` if `inner` lies entirely within `outer` and is not empty.
+pub fn contains_rect(outer: Rect, inner: Rect) -> bool {
+    inner.size.width > 0.0
+        && inner.size.height > 0.0
+        && inner.origin.x >= outer.origin.x
+        && inner.origin.y >= outer.origin.y
+        && max_x(inner) <= max_x(outer)
+        && max_y(inner) <= max_y(outer)
+}
+
+/// Captures the given portion of the virtual desktop, in points. Areas not
+/// covered by any display come out black.
+///
+/// Displays aren't enumerated again here, as this is called for every frame
+/// of a stream, so `rect` must already have been checked against the bounds
+/// returned by `displays`.
+pub fn capture(rect: Rect) -> ImageResult<autopilot::bitmap::Bitmap> {
+    if !(rect.size.width > 0.0 && rect.size.height > 0.0) {
+        return Err(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+        )));
+    }
+    let image = platform::capture(rect)?;
+    let scale = image.width() as f64 / rect.size.width;
+    Ok(autopilot::bitmap::Bitmap::new(image, Some(scale)))
+}
+
+fn max_x(rect: Rect) -> f64 {
+    rect.origin.x + rect.size.width
+}
+
+fn max_y(rect: Rect) -> f64 {
+    rect.origin.y + rect.size.height
+}
+
+#[cfg(any(target_os = "macos", windows))]
+fn capture_error(message: &str) -> ImageError {
+    ImageError::IoError(std::io::Error::other(message))
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::Display;
+    use autopilot::geometry::{Point, Rect, Size};
+    use image::{DynamicImage, ImageResult};
+    use std::os::raw::c_int;
+    use std::ptr;
+    use x11::{xinerama, xlib, xrandr};
+
+    /// Monitor bounds in pixels, and whether the monitor is primary.
+    type Monitor = ((i32, i32, i32, i32), bool);
+
+    pub fn displays() -> Vec<Display> {
+        let monitors = unsafe {
+            let display = xlib::XOpenDisplay(ptr::null());
+            if display.is_null() {
+                return Vec::new();
+            }
+            let monitors = randr_monitors(display).or_else(|| xinerama_monitors(display));
+            xlib::XCloseDisplay(display);
+            monitors.unwrap_or_default()
+        };
+
+        // X11 has a single scale for the whole root window.
+        let scale = autopilot::screen::scale();
+        monitors
+            .into_iter()
+            .enumerate()
+            .map(|(id, ((x, y, width, height), is_primary))| Display {
+                id: id as u32,
+                bounds: Rect::new(
+                    Point::new(x as f64 / scale, y as f64 / scale),
+                    Size::new(width as f64 / scale, height as f64 / scale),
+                ),
+                scale,
+                is_primary,
+            })
+            .collect()
+    }
+
+    /// The root window spans every monitor, so `autopilot` can capture any
+    /// part of it.
+    pub fn capture(rect: Rect) -> ImageResult<DynamicImage> {
+        autopilot::bitmap::capture_screen_portion(rect).map(|bitmap| bitmap.image)
+    }
+
+    /// Returns the monitors reported by RandR, which requires version 1.5.
+    unsafe fn randr_monitors(display: *mut xlib::Display) -> Option<Vec<Monitor>> {
+        let (mut event_base, mut error_base) = (0, 0);
+        let (mut major, mut minor) = (0, 0);
+        unsafe {
+            if xrandr::XRRQueryExtension(display, &mut event_base, &mut error_base) == 0
+                || xrandr::XRRQueryVersion(display, &mut major, &mut minor) == 0
+                || (major, minor) < (1, 5)
+            {
+                return None;
+            }
+
+            let root = xlib::XDefaultRootWindow(display);
+            let mut count: c_int = 0;
+            let info = xrandr::XRRGetMonitors(display, root, xlib::True, &mut count);
+            if info.is_null() {
+                return None;
+            }
+            let monitors = std::slice::from_raw_parts(info, count.max(0) as usize)
+                .iter()
+                .map(|m| ((m.x, m.y, m.width, m.height), m.primary != 0))
+                .collect::<Vec<Monitor>>();
+            xrandr::XRRFreeMonitors(info);
+            Some(monitors).filter(|monitors| !monitors.is_empty())
+        }
+    }
+
+    /// Returns the screens reported by Xinerama, which has no notion of a
+    /// primary screen, so the first is used.
+    unsafe fn xinerama_monitors(display: *mut xlib::Display) -> Option<Vec<Monitor>> {
+        let (mut event_base, mut error_base) = (0, 0);
+        unsafe {
+            if xinerama::XineramaQueryExtension(display, &mut event_base, &mut error_base) == 0
+                || xinerama::XineramaIsActive(display) == 0
+            {
+                return None;
+            }
+
+            let mut count: c_int = 0;
+            let info = xinerama::XineramaQueryScreens(display, &mut count);
+            if info.is_null() {
+                return None;
+            }
+            let monitors = std::slice::from_raw_parts(info, count.max(0) as usize)
+                .iter()
+                .enumerate()
+                .map(|(index, s)| {
+                    let bounds = (
+                        s.x_org as i32,
+                        s.y_org as i32,
+                        s.width as i32,
+                        s.height as i32,
+                    );
+                    (bounds, index == 0)
+                })
+                .collect::<Vec<Monitor>>();
+            xlib::XFree(info.cast());
+            Some(monitors).filter(|monitors| !monitors.is_empty())
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::{capture_error, Display};
+    use autopilot::geometry::{Point, Rect, Size};
+    use core_graphics::display::{
+        kCGNullWindowID, kCGWindowImageDefault, kCGWindowListOptionOnScreenOnly, CGDisplay,
+        CGPoint, CGRect, CGSize,
+    };
+    use image::{DynamicImage, ImageResult, RgbaImage};
+
+    pub fn displays() -> Vec<Display> {
+        CGDisplay::active_displays()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|id| {
+                let display = CGDisplay::new(id);
+                let bounds = display.bounds();
+                let scale = display
+                    .display_mode()
+                    .filter(|mode| mode.width() > 0)
+                    .map_or(1.0, |mode| mode.pixel_width() as f64 / mode.width() as f64);
+                Display {
+                    id,
+                    bounds: Rect::new(
+                        Point::new(bounds.origin.x, bounds.origin.y),
+                        Size::new(bounds.size.width, bounds.size.height),
+                    ),
+                    scale,
+                    is_primary: display.is_main(),
+                }
+            })
+            .collect()
+    }
+
+    /// Captures at the highest scale of the displays the rect covers.
+    pub fn capture(rect: Rect) -> ImageResult<DynamicImage> {
+        let bounds = CGRect::new(
+            &CGPoint::new(rect.origin.x, rect.origin.y),
+            &CGSize::new(rect.size.width, rect.size.height),
+        );
+        let image = CGDisplay::screenshot(
+            bounds,
+            kCGWindowListOptionOnScreenOnly,
+            kCGNullWindowID,
+            kCGWindowImageDefault,
+        )
+        .ok_or_else(|| capture_error("Could not capture screen"))?;
+
+        let (width, bytes_per_row) = (image.width(), image.bytes_per_row());
+        let data = image.data();
+        let bytes = data.bytes();
+        let mut buffer = RgbaImage::new(width as u32, image.height() as u32);
+        for (y, row) in buffer.rows_mut().enumerate() {
+            for (x, pixel) in row.enumerate() {
+                let i = y * bytes_per_row + x * 4;
+                pixel.0 = [bytes[i + 2], bytes[i + 1], bytes[i], 255];
+            }
+        }
+        Ok(DynamicImage::ImageRgba8(buffer))
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::{capture_error, Display};
+    use autopilot::geometry::{Point, Rect, Size};
+    use image::{DynamicImage, ImageResult, RgbaImage};
+    use std::{mem, ptr};
+    use winapi::shared::minwindef::{BOOL, LPARAM, TRUE};
+    use winapi::shared::windef::{HDC, HGDIOBJ, HMONITOR, LPRECT};
+    use winapi::shared::winerror::S_OK;
+    use winapi::um::shellscalingapi::{GetDpiForMonitor, MDT_EFFECTIVE_DPI};
+    use winapi::um::wingdi::{
+        BitBlt, CreateCompatibleBitmap, CreateCompatibleDC, DeleteDC, DeleteObject, GetDIBits,
+        SelectObject, BITMAPINFO, BITMAPINFOHEADER, BI_RGB, CAPTUREBLT, DIB_RGB_COLORS, SRCCOPY,
+    };
+    use winapi::um::winuser::{
+        EnumDisplayMonitors, GetDC, GetMonitorInfoW, ReleaseDC, MONITORINFO, MONITORINFOF_PRIMARY,
+    };
+
+    /// Pixels per point at the standard 96 DPI.
+    const BASE_DPI: f64 = 96.0;
+
+    pub fn displays() -> Vec<Display> {
+        let mut monitors: Vec<HMONITOR> = Vec::new();
+        unsafe {
+            EnumDisplayMonitors(
+                ptr::null_mut(),
+                ptr::null(),
+                Some(collect_monitor),
+                &mut monitors as *mut Vec<HMONITOR> as LPARAM,
+            );
+        }
+
+        // Monitor rects are in the coordinates of the whole desktop, which
+        // `autopilot` converts to points using the system scale.
+        let system_scale = autopilot::screen::scale();
+        monitors
+            .into_iter()
+            .enumerate()
+            .filter_map(|(id, monitor)| {
+                let mut info: MONITORINFO = unsafe { mem::zeroed() };
+                info.cbSize = mem::size_of::<MONITORINFO>() as u32;
+                if unsafe { GetMonitorInfoW(monitor, &mut info) } == 0 {
+                    return None;
+                }
+                let (mut dpi_x, mut dpi_y) = (0, 0);
+                let result =
+                    unsafe { GetDpiForMonitor(monitor, MDT_EFFECTIVE_DPI, &mut dpi_x, &mut dpi_y) };
+                let scale = if result == S_OK {
+                    dpi_x as f64 / BASE_DPI
+                } else {
+                    system_scale
+                };
+                let rect = info.rcMonitor;
+                Some(Display {
+                    id: id as u32,
+                    bounds: Rect::new(
+                        Point::new(rect.left as f64, rect.top as f64).scaled(1.0 / system_scale),
+                        Size::new((rect.right - rect.left) as f64, (rect.bottom - rect.top) as f64)
+                            .scaled(1.0 / system_scale),
+                    ),
+                    scale,
+                    is_primary: info.dwFlags & MONITORINFOF_PRIMARY != 0,
+                })
+            })
+            .collect()
+    }
+
+    unsafe extern "system" fn collect_monitor(
+        monitor: HMONITOR,
+        _: HDC,
+        _: LPRECT,
+        data: LPARAM,
+    ) -> BOOL {
+        let monitors = unsafe { &mut *(data as *mut Vec<HMONITOR>) };
+        monitors.push(monitor);
+        TRUE
+    }
+
+    /// Captures at the system scale, which `autopilot` also converts points
+    /// with, rather than at the DPI of the monitors the rect covers. Bitmaps
+    /// of a monitor whose DPI differs from the system's therefore have the
+    /// system scale rather than that monitor's `Display::scale`.
+    pub fn capture(rect: Rect) -> ImageResult<DynamicImage> {
+        let scale = autopilot::screen::scale();
+        let rect = Rect::new(rect.origin.scaled(scale), rect.size.scaled(scale));
+        let (x, y) = (rect.origin.x.round() as i32, rect.origin.y.round() as i32);
+        let width = (rect.size.width.round() as i32).max(1);
+        let height = (rect.size.height.round() as i32).max(1);
+        let mut data = vec![0u8; width as usize * height as usize * 4];
+
+        let copied = unsafe {
+            let screen = GetDC(ptr::null_mut());
+            let memory = CreateCompatibleDC(screen);
+            let bitmap = CreateCompatibleBitmap(screen, width, height);
+            let previous = SelectObject(memory, bitmap as HGDIOBJ);
+            let blitted = BitBlt(memory, 0, 0, width, height, screen, x, y, SRCCOPY | CAPTUREBLT);
+            SelectObject(memory, previous);
+
+            // A negative height requests rows from top to bottom.
+            let mut info: BITMAPINFO = mem::zeroed();
+            info.bmiHeader = BITMAPINFOHEADER {
+                biSize: mem::size_of::<BITMAPINFOHEADER>() as u32,
+                biWidth: width,
+                biHeight: -height,
+                biPlanes: 1,
+                biBitCount: 32,
+                biCompression: BI_RGB,
+                ..mem::zeroed()
+            };
+            let lines = GetDIBits(
+                memory,
+                bitmap,
+                0,
+                height as u32,
+                data.as_mut_ptr().cast(),
+                &mut info,
+                DIB_RGB_COLORS,
+            );
+
+            DeleteObject(bitmap as HGDIOBJ);
+            DeleteDC(memory);
+            ReleaseDC(ptr::null_mut(), screen);
+            blitted != 0 && lines == height
+        };
+        if !copied {
+            return Err(capture_error("Could not capture screen"));
+        }
+
+        // Convert from BGRX.
+        for pixel in data.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+            pixel[3] = 255;
+        }
+        RgbaImage::from_raw(width as u32, height as u32, data)
+            .map(DynamicImage::ImageRgba8)
+            .ok_or_else(|| capture_error("Could not capture screen"))
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Color space and filter operations, applied either one at a time or as a
+//! pipeline to both the haystack and needle of a search.
+
+use image::DynamicImage;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    /// Converts to grayscale, keeping any alpha channel.
+    Grayscale,
+    /// Converts to grayscale, then makes pixels at least as bright as the
+    /// level white and the rest black. Keeps any alpha channel.
+    Threshold(u8),
+    /// Inverts the color channels, keeping any alpha channel.
+    Invert,
+    /// Applies a Gaussian blur with the given standard deviation, in pixels.
+    Blur(f32),
+}
+
+/// Returns a copy of `image` with each filter applied in turn.
+pub fn apply(image: &DynamicImage, filters: &[Filter]) -> DynamicImage {
+    filters
+        .iter()
+        .fold(image.clone(), |image, &filter| apply_one(image, filter))
+}
+
+fn apply_one(image: DynamicImage, filter: Filter) -> DynamicImage {
+    match filter {
+        Filter::Grayscale => image.grayscale(),
+        Filter::Threshold(level) => {
+            let binarize = |x: u8| if x >= level { 255 } else { 0 };
+            if image.color().has_alpha() {
+                let mut luma = image.to_luma_alpha8();
+                luma.pixels_mut().for_each(|p| p[0] = binarize(p[0]));
+                DynamicImage::ImageLumaA8(luma)
+            } else {
+                let mut luma = image.to_luma8();
+                luma.pixels_mut().for_each(|p| p[0] = binarize(p[0]));
+                DynamicImage::ImageLuma8(luma)
+            }
+        }
+        Filter::Invert => {
+            let mut image = image;
+            image.invert();
+            image
+        }
+        Filter::Blur(sigma) => image.blur(sigma),
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! 64-bit perceptual hashes. Unlike a hash of the exact pixel data, similar
+//! looking images have hashes differing in only a few bits, so the Hamming
+//! distance between two hashes measures how alike the images are.
+
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage};
+use std::f64::consts::PI;
+
+/// Width and height of the grid of bits making up a hash.
+const HASH_SIZE: u32 = 8;
+
+/// Width and height of the image transformed by the DCT hash, of which only
+/// the lowest `HASH_SIZE` frequencies are kept.
+const DCT_SIZE: u32 = 32;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum HashMethod {
+    /// Each bit is set if a pixel is brighter than the mean.
+    Average,
+    /// Each bit is set if a pixel is brighter than its neighbour to the left.
+    Difference,
+    /// Each bit is set if a low frequency of the discrete cosine transform is
+    /// above the median. The most robust to rescaling and recompression.
+    Dct,
+}
+
+impl HashMethod {
+    pub fn from_name(name: &str) -> Option<HashMethod> {
+        match name.to_lowercase().as_str() {
+            "average" => Some(HashMethod::Average),
+            "difference" => Some(HashMethod::Difference),
+            "dct" => Some(HashMethod::Dct),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the perceptual hash of `image`. Bits are ordered row by row, with
+/// the top-left bit being the most significant.
+pub fn perceptual_hash(image: &DynamicImage, method: HashMethod) -> u64 {
+    let luma = image.to_luma8();
+    match method {
+        HashMethod::Average => {
+            let pixels = shrunk(&luma, HASH_SIZE, HASH_SIZE);
+            let mean = pixels.iter().sum::<f64>() / pixels.len() as f64;
+            bits(pixels.iter().map(|&x| x > mean))
+        }
+        HashMethod::Difference => {
+            let pixels = shrunk(&luma, HASH_SIZE + 1, HASH_SIZE);
+            let rows = pixels.chunks(HASH_SIZE as usize + 1);
+            bits(rows.flat_map(|row| row.windows(2).map(|pair| pair[1] > pair[0])))
+        }
+        HashMethod::Dct => {
+            let pixels = shrunk(&luma, DCT_SIZE, DCT_SIZE);
+            let coefficients = low_frequencies(&pixels);
+            let mut sorted = coefficients.clone();
+            sorted.sort_by(|a, b| a.total_cmp(b));
+            let middle = sorted.len() / 2;
+            let median = (sorted[middle - 1] + sorted[middle]) / 2.0;
+            bits(coefficients.iter().map(|&x| x > median))
+        }
+    }
+}
+
+/// Returns the number of bits that differ between two hashes.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Resizes the image to the given dimensions, returning its pixels row by
+/// row.
+fn shrunk(image: &GrayImage, width: u32, height: u32) -> Vec<f64> {
+    imageops::resize(image, width, height, FilterType::Triangle)
+        .into_raw()
+        .into_iter()
+        .map(f64::from)
+        .collect()
+}
+
+/// Returns the top-left `HASH_SIZE` square of the 2D DCT-II of a `DCT_SIZE`
+/// square of pixels, row by row.
+fn low_frequencies(pixels: &[f64]) -> Vec<f64> {
+    let size = DCT_SIZE as usize;
+    let kept = HASH_SIZE as usize;
+    let basis: Vec<f64> = (0..kept)
+        .flat_map(|k| {
+            (0..size).map(move |n| (PI / size as f64 * (n as f64 + 0.5) * k as f64).cos())
+        })
+        .collect();
+
+    // Transform the rows, then the columns of the result.
+    let mut rows = vec![0.0; size * kept];
+    for y in 0..size {
+        for k in 0..kept {
+            rows[y * kept + k] = (0..size)
+                .map(|n| pixels[y * size + n] * basis[k * size + n])
+                .sum();
+        }
+    }
+    let mut coefficients = vec![0.0; kept * kept];
+    for k in 0..kept {
+        for x in 0..kept {
+            coefficients[k * kept + x] = (0..size)
+                .map(|n| rows[n * kept + x] * basis[k * size + n])
+                .sum();
+        }
+    }
+    coefficients
+}
+
+fn bits<I: Iterator<Item = bool>>(bits: I) -> u64 {
+    bits.fold(0, |hash, bit| (hash << 1) | bit as u64)
+}
@@ -6,10 +6,21 @@
 // copied, modified, or distributed except according to those terms.
 
 use image::error::{ImageError, LimitErrorKind};
+use pyo3::create_exception;
 use pyo3::prelude::*;
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct FromImageError(ImageError);
 
+create_exception!(
+    autopy,
+    TimeoutError,
+    pyo3::exceptions::PyTimeoutError,
+    "Raised when waiting for something to happen on screen takes longer than \
+     the given timeout."
+);
+
 pub fn rgb_to_hex(red: u8, green: u8, blue: u8) -> u32 {
     ((red as u32) << 16) | ((green as u32) << 8) | blue as u32
 }
@@ -37,3 +48,66 @@ impl From<FromImageError> for PyErr {
         }
     }
 }
+
+/// Converts a non-negative number of seconds to a `Duration`, raising
+/// `ValueError` if it is too large to represent. `name` describes the value in
+/// the error message.
+pub fn duration_from_secs(seconds: f64, name: &str) -> PyResult<Duration> {
+    Duration::try_from_secs_f64(seconds).map_err(|_| {
+        pyo3::exceptions::PyValueError::new_err(format!(
+            "{} of {} seconds is too long",
+            name, seconds
+        ))
+    })
+}
+
+/// Calls `attempt` with the GIL released every `interval` seconds until it
+/// returns a value, raising `TimeoutError` if it hasn't after `timeout`
+/// seconds. `attempt` is always called at least once, and once more at the
+/// deadline. Signals such as `KeyboardInterrupt` are handled between attempts.
+pub fn wait_until<T, F>(
+    py: Python<'_>,
+    timeout: f64,
+    interval: f64,
+    waiting_for: &str,
+    mut attempt: F,
+) -> PyResult<T>
+where
+    T: Send,
+    F: FnMut() -> PyResult<Option<T>> + Send,
+{
+    if !(timeout >= 0.0 && timeout.is_finite()) {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Timeout must be a non-negative number of seconds, got {}",
+            timeout
+        )));
+    }
+    if !(interval > 0.0 && interval.is_finite()) {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Interval must be a positive number of seconds, got {}",
+            interval
+        )));
+    }
+
+    let deadline = duration_from_secs(timeout, "Timeout")?;
+    let interval = duration_from_secs(interval, "Interval")?;
+    let start = Instant::now();
+    loop {
+        let attempted_at = Instant::now();
+        if let Some(value) = py.detach(&mut attempt)? {
+            return Ok(value);
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= deadline {
+            return Err(TimeoutError::new_err(format!(
+                "Timed out after {} seconds waiting for {}",
+                timeout, waiting_for
+            )));
+        }
+        py.check_signals()?;
+        let pause = interval
+            .saturating_sub(attempted_at.elapsed())
+            .min(deadline - elapsed);
+        py.detach(|| thread::sleep(pause));
+    }
+}
@@ -13,12 +13,20 @@ extern crate image;
 extern crate pyo3;
 
 pub mod alert;
+mod animation;
 pub mod bitmap;
 pub mod color;
+mod display;
+mod filters;
+mod hashing;
 mod internal;
 pub mod key;
+mod matching;
 pub mod mouse;
+mod palette;
+mod regions;
 pub mod screen;
+mod stream;
 
 use pyo3::prelude::*;
 
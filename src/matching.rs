@@ -0,0 +1,743 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Scored template matching. Unlike the per-pixel tolerance used by
+//! `autopilot`, every candidate position is given a similarity score, so a
+//! handful of differing pixels lowers the score rather than rejecting the
+//! match outright.
+
+use image::{GrayImage, RgbaImage};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Largest possible distance between two RGB colors, which `autopilot`'s
+/// tolerance of 1 corresponds to.
+const MAX_TOLERANCE_DELTA: f64 = 441.672_955_930_063_7;
+
+/// Mask and alpha values below this mark a needle pixel as "don't care".
+const MASK_THRESHOLD: u8 = 128;
+
+/// Number of bytes whose products are summed at a time when correlating,
+/// chosen so that the sum fits in 32 bits.
+const CROSS_CHUNK: usize = 16384;
+
+/// Number of threads searches are split across. 1 searches serially.
+static SEARCH_THREADS: AtomicUsize = AtomicUsize::new(1);
+
+/// Windows whose summed variance falls below this are considered flat, in
+/// which case the cross-correlation is undefined.
+const FLAT_VARIANCE: f64 = 1e-6;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MatchMethod {
+    /// Zero-normalized cross-correlation, in the range -1 to 1. Insensitive to
+    /// uniform changes in brightness and contrast. A uniform window scores 1
+    /// against a uniform template and 0 against any other.
+    Ncc,
+    /// One minus the root mean square difference between pixels, in the range
+    /// 0 to 1.
+    Sqdiff,
+}
+
+impl MatchMethod {
+    pub fn from_name(name: &str) -> Option<MatchMethod> {
+        match name.to_lowercase().as_str() {
+            "ncc" => Some(MatchMethod::Ncc),
+            "sqdiff" => Some(MatchMethod::Sqdiff),
+            _ => None,
+        }
+    }
+}
+
+/// A candidate position (in pixels) of a template in the haystack.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Match {
+    pub x: u32,
+    pub y: u32,
+    pub score: f64,
+}
+
+pub fn search_threads() -> usize {
+    SEARCH_THREADS.load(Ordering::Relaxed)
+}
+
+pub fn set_search_threads(threads: usize) {
+    SEARCH_THREADS.store(threads.max(1), Ordering::Relaxed);
+}
+
+/// Returns which needle pixels take part in matching, given either an explicit
+/// grayscale mask (white pixels are compared) or the needle's own alpha
+/// channel. Returns `None` if every pixel is compared.
+pub fn needle_mask(needle: &RgbaImage, mask: Option<&GrayImage>) -> Option<Vec<bool>> {
+    let mask: Vec<bool> = match mask {
+        Some(mask) => mask.pixels().map(|p| p[0] >= MASK_THRESHOLD).collect(),
+        None => needle.pixels().map(|p| p[3] >= MASK_THRESHOLD).collect(),
+    };
+    if mask.iter().all(|&x| x) {
+        None
+    } else {
+        Some(mask)
+    }
+}
+
+/// Returns `true` if the RGB components of the given colors are within
+/// `tolerance` of each other, as defined by `autopilot`.
+pub fn colors_match(a: &[u8], b: &[u8], tolerance: f64) -> bool {
+    if tolerance <= 0.0 {
+        return a[..3] == b[..3];
+    }
+    let distance: f64 = (0..3)
+        .map(|c| {
+            let delta = a[c] as f64 - b[c] as f64;
+            delta * delta
+        })
+        .sum::<f64>()
+        .sqrt();
+    distance <= tolerance * MAX_TOLERANCE_DELTA
+}
+
+/// A needle prepared for matching, with its statistics computed up front.
+/// Pixels outside the mask are ignored entirely.
+pub struct Template {
+    width: u32,
+    height: u32,
+    /// RGBA bytes, with the alpha channel and masked out pixels zeroed so
+    /// that only compared values contribute to dot products.
+    pixels: Vec<u8>,
+    mask: Option<Vec<bool>>,
+    count: f64,
+    mean: [f64; 3],
+    sum_sq: f64,
+    variance: f64,
+}
+
+impl Template {
+    pub fn new(image: &RgbaImage, mask: Option<Vec<bool>>) -> Template {
+        let mut pixels = image.as_raw().clone();
+        let mut count: u64 = 0;
+        let mut sum = [0u64; 3];
+        let mut sum_sq: u64 = 0;
+        for (i, pixel) in pixels.chunks_exact_mut(4).enumerate() {
+            pixel[3] = 0;
+            if mask.as_ref().is_some_and(|m| !m[i]) {
+                pixel.fill(0);
+                continue;
+            }
+            count += 1;
+            for c in 0..3 {
+                sum[c] += pixel[c] as u64;
+                sum_sq += pixel[c] as u64 * pixel[c] as u64;
+            }
+        }
+        let count = count.max(1);
+        Template {
+            width: image.width(),
+            height: image.height(),
+            pixels,
+            mask,
+            count: count as f64,
+            mean: sum.map(|x| x as f64 / count as f64),
+            sum_sq: sum_sq as f64,
+            variance: variance(count, sum, sum_sq),
+        }
+    }
+}
+
+/// Returns the sum of squared deviations from the mean over the three
+/// channels of `count` pixels, computed exactly from the channels' sums and
+/// summed squares.
+fn variance(count: u64, sum: [u64; 3], sum_sq: u64) -> f64 {
+    let scaled = count as u128 * sum_sq as u128;
+    let squared: u128 = sum.iter().map(|&x| x as u128 * x as u128).sum();
+    scaled.saturating_sub(squared) as f64 / count as f64
+}
+
+/// A haystack prepared for scored matching, with summed-area tables of its
+/// pixels and their squares so that the mean and variance of any window are
+/// found in constant time.
+pub struct Haystack<'a> {
+    image: &'a RgbaImage,
+    /// Per-channel sums of the pixels above and to the left of each position,
+    /// in rows of `image.width() + 1`. These wrap on overflow, which leaves
+    /// the sums of windows exact as long as they fit.
+    sums: Vec<[u32; 3]>,
+    /// Sums of the squares of every channel, arranged as `sums`.
+    squares: Vec<u64>,
+}
+
+impl<'a> Haystack<'a> {
+    pub fn new(image: &'a RgbaImage) -> Haystack<'a> {
+        let columns = image.width() as usize + 1;
+        let rows = image.height() as usize + 1;
+        let mut sums = vec![[0u32; 3]; columns * rows];
+        let mut squares = vec![0u64; columns * rows];
+        for (y, row) in image.rows().enumerate() {
+            let mut row_sum = [0u32; 3];
+            let mut row_squares: u64 = 0;
+            for (x, pixel) in row.enumerate() {
+                for c in 0..3 {
+                    row_sum[c] = row_sum[c].wrapping_add(pixel[c] as u32);
+                    row_squares = row_squares.wrapping_add(pixel[c] as u64 * pixel[c] as u64);
+                }
+                let above = y * columns + x + 1;
+                let i = above + columns;
+                for c in 0..3 {
+                    sums[i][c] = sums[above][c].wrapping_add(row_sum[c]);
+                }
+                squares[i] = squares[above].wrapping_add(row_squares);
+            }
+        }
+        Haystack {
+            image,
+            sums,
+            squares,
+        }
+    }
+
+    /// Returns the per-channel sums and the summed squares of the window of
+    /// the given size whose top-left corner is at `(x, y)`.
+    fn window(&self, x: u32, y: u32, width: u32, height: u32) -> ([u64; 3], u64) {
+        let columns = self.image.width() as usize + 1;
+        let top_left = y as usize * columns + x as usize;
+        let top_right = top_left + width as usize;
+        let bottom_left = top_left + height as usize * columns;
+        let bottom_right = bottom_left + width as usize;
+        let sum = |c: usize| {
+            self.sums[bottom_right][c]
+                .wrapping_sub(self.sums[top_right][c])
+                .wrapping_sub(self.sums[bottom_left][c])
+                .wrapping_add(self.sums[top_left][c]) as u64
+        };
+        let sum_sq = self.squares[bottom_right]
+            .wrapping_sub(self.squares[top_right])
+            .wrapping_sub(self.squares[bottom_left])
+            .wrapping_add(self.squares[top_left]);
+        ([sum(0), sum(1), sum(2)], sum_sq)
+    }
+
+    /// Like `window`, but only including the pixels under the template's
+    /// mask.
+    fn masked_window(&self, template: &Template, mask: &[bool], x: u32, y: u32) -> ([u64; 3], u64) {
+        let mut sum = [0u64; 3];
+        let mut sum_sq: u64 = 0;
+        for (row, haystack_row) in self.rows(template, x, y).enumerate() {
+            let mask_row = &mask[row * template.width as usize..][..template.width as usize];
+            let pixels = haystack_row.chunks_exact(4).zip(mask_row);
+            for (pixel, _) in pixels.filter(|&(_, &care)| care) {
+                for c in 0..3 {
+                    sum[c] += pixel[c] as u64;
+                    sum_sq += pixel[c] as u64 * pixel[c] as u64;
+                }
+            }
+        }
+        (sum, sum_sq)
+    }
+
+    /// Returns the dot product of the template and the window whose top-left
+    /// corner is at `(x, y)`.
+    fn cross(&self, template: &Template, x: u32, y: u32) -> u64 {
+        let needle_rows = template.pixels.chunks_exact(template.width as usize * 4);
+        self.rows(template, x, y)
+            .zip(needle_rows)
+            .flat_map(|(haystack_row, needle_row)| {
+                // Summed in chunks small enough not to overflow 32 bits, which
+                // vectorize better than 64-bit sums.
+                let chunks = haystack_row
+                    .chunks(CROSS_CHUNK)
+                    .zip(needle_row.chunks(CROSS_CHUNK));
+                chunks.map(|(a, b)| {
+                    a.iter()
+                        .zip(b)
+                        .map(|(&a, &b)| a as u32 * b as u32)
+                        .sum::<u32>() as u64
+                })
+            })
+            .sum()
+    }
+
+    /// Returns the rows of RGBA bytes of the window under the template whose
+    /// top-left corner is at `(x, y)`.
+    fn rows(&self, template: &Template, x: u32, y: u32) -> impl Iterator<Item = &[u8]> {
+        let raw = self.image.as_raw();
+        let stride = self.image.width() as usize * 4;
+        let row_len = template.width as usize * 4;
+        (0..template.height as usize).map(move |row| {
+            let start = (y as usize + row) * stride + x as usize * 4;
+            &raw[start..start + row_len]
+        })
+    }
+}
+
+/// Range of candidate top-left positions in the haystack, end exclusive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl Region {
+    /// Returns the positions at which a needle of the given pixel size fits
+    /// entirely inside the given pixel rect of `haystack`, clamped to the
+    /// haystack's bounds.
+    pub fn new(haystack: &RgbaImage, needle: (u32, u32), rect: (u32, u32, u32, u32)) -> Region {
+        let (x, y, width, height) = rect;
+        let max_x = x.saturating_add(width).min(haystack.width());
+        let max_y = y.saturating_add(height).min(haystack.height());
+        Region {
+            x0: x,
+            y0: y,
+            x1: (max_x + 1).saturating_sub(needle.0).max(x),
+            y1: (max_y + 1).saturating_sub(needle.1).max(y),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.x0 >= self.x1 || self.y0 >= self.y1
+    }
+}
+
+/// Returns the similarity of `template` to the haystack window whose top-left
+/// corner is at `(x, y)`. The window must lie inside the haystack.
+pub fn score_at(
+    haystack: &Haystack,
+    template: &Template,
+    x: u32,
+    y: u32,
+    method: MatchMethod,
+) -> f64 {
+    let (sum, sum_sq) = match &template.mask {
+        Some(mask) => haystack.masked_window(template, mask, x, y),
+        None => haystack.window(x, y, template.width, template.height),
+    };
+    let cross = haystack.cross(template, x, y) as f64;
+
+    let count = template.count;
+    match method {
+        MatchMethod::Sqdiff => {
+            let total = sum_sq as f64 - 2.0 * cross + template.sum_sq;
+            1.0 - (total.max(0.0) / (3.0 * count)).sqrt() / 255.0
+        }
+        MatchMethod::Ncc => {
+            let variance = variance(count as u64, sum, sum_sq);
+            let is_flat = variance < FLAT_VARIANCE;
+            if is_flat || template.variance < FLAT_VARIANCE {
+                // Uniform regions carry no pattern to correlate; they are
+                // only considered alike if both are uniform.
+                if is_flat && template.variance < FLAT_VARIANCE {
+                    1.0
+                } else {
+                    0.0
+                }
+            } else {
+                let means: f64 = (0..3).map(|c| template.mean[c] * sum[c] as f64).sum();
+                let covariance = cross - means;
+                (covariance / (variance * template.variance).sqrt()).clamp(-1.0, 1.0)
+            }
+        }
+    }
+}
+
+/// Returns every position in `region` scoring at least `threshold`, in
+/// row-major order.
+pub fn find_all(
+    haystack: &Haystack,
+    template: &Template,
+    region: Region,
+    method: MatchMethod,
+    threshold: f64,
+) -> Vec<Match> {
+    if region.is_empty() || template.pixels.is_empty() {
+        return Vec::new();
+    }
+    in_row_bands(region, |band| {
+        let mut matches = Vec::new();
+        for y in band.y0..band.y1 {
+            for x in band.x0..band.x1 {
+                let score = score_at(haystack, template, x, y, method);
+                if score >= threshold {
+                    matches.push(Match { x, y, score });
+                }
+            }
+        }
+        matches
+    })
+}
+
+/// Returns the highest scoring position in `region`, preferring the first in
+/// row-major order on ties.
+pub fn find_best(
+    haystack: &Haystack,
+    template: &Template,
+    region: Region,
+    method: MatchMethod,
+) -> Option<Match> {
+    if region.is_empty() || template.pixels.is_empty() {
+        return None;
+    }
+    // Each band yields its own best match; reducing them in band order keeps
+    // the serial tie-breaking.
+    let candidates = in_row_bands(region, |band| {
+        let mut best: Option<Match> = None;
+        for y in band.y0..band.y1 {
+            for x in band.x0..band.x1 {
+                let score = score_at(haystack, template, x, y, method);
+                if best.is_none_or(|m| score > m.score) {
+                    best = Some(Match { x, y, score });
+                }
+            }
+        }
+        best.into_iter().collect()
+    });
+    candidates
+        .into_iter()
+        .reduce(|best, m| if m.score > best.score { m } else { best })
+}
+
+/// Returns `true` if the given colors match within `tolerance` the way
+/// `autopilot` compares needle pixels: at a tolerance of 0 every channel,
+/// alpha included, must be equal, and otherwise only the RGB distance counts.
+pub fn pixels_match(a: &[u8], b: &[u8], tolerance: f64) -> bool {
+    if tolerance <= 0.0 {
+        return a[..4] == b[..4];
+    }
+    colors_match(a, b, tolerance)
+}
+
+/// Returns `true` if every needle pixel in `mask`, or every pixel if there is
+/// no mask, matches the haystack window whose top-left corner is at `(x, y)`
+/// within `tolerance`.
+pub fn needle_matches_at(
+    haystack: &RgbaImage,
+    needle: &RgbaImage,
+    mask: Option<&[bool]>,
+    x: u32,
+    y: u32,
+    tolerance: f64,
+) -> bool {
+    let raw = haystack.as_raw();
+    let stride = haystack.width() as usize * 4;
+    let width = needle.width() as usize;
+    for (row, needle_row) in needle.as_raw().chunks_exact(width * 4).enumerate() {
+        let start = (y as usize + row) * stride + x as usize * 4;
+        let haystack_row = &raw[start..start + width * 4];
+        let mask_row = mask.map(|m| &m[row * width..(row + 1) * width]);
+        let pixels = haystack_row.chunks_exact(4).zip(needle_row.chunks_exact(4));
+        for (i, (pixel, needle_pixel)) in pixels.enumerate() {
+            let care = mask_row.is_none_or(|m| m[i]);
+            if care && !pixels_match(pixel, needle_pixel, tolerance) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Returns the positions in `region` at which the needle matches, ignoring
+/// pixels outside `mask` if given, in the order described for `scan`. Stops
+/// after `limit` matches if given.
+pub fn find_every_needle(
+    haystack: &RgbaImage,
+    needle: &RgbaImage,
+    mask: Option<&[bool]>,
+    region: Region,
+    start: (u32, u32),
+    tolerance: f64,
+    limit: Option<usize>,
+) -> Vec<(u32, u32)> {
+    if region.is_empty() {
+        return Vec::new();
+    }
+    let mut points = in_column_bands(region, |band| {
+        scan(band, start, limit, |x, y| {
+            needle_matches_at(haystack, needle, mask, x, y, tolerance)
+        })
+    });
+    points.truncate(limit.unwrap_or(points.len()));
+    points
+}
+
+/// A color searched for by `find_every_color`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorQuery {
+    pub color: [u8; 3],
+    pub tolerance: f64,
+    /// Alpha value that pixels must also match within the tolerance, if any.
+    pub alpha: Option<u8>,
+    /// Whether pixels that are more than half transparent may match.
+    pub match_transparent: bool,
+}
+
+impl ColorQuery {
+    pub fn matches(&self, pixel: &[u8]) -> bool {
+        if !self.match_transparent && pixel[3] < MASK_THRESHOLD {
+            return false;
+        }
+        if let Some(alpha) = self.alpha {
+            let delta = (pixel[3] as f64 - alpha as f64).abs();
+            if delta > self.tolerance * u8::MAX as f64 {
+                return false;
+            }
+        }
+        colors_match(pixel, &self.color, self.tolerance)
+    }
+}
+
+/// Returns the positions in `region` whose color matches `query`, in the
+/// order described for `scan`, stopping after `limit` matches if given.
+pub fn find_every_color(
+    haystack: &RgbaImage,
+    query: ColorQuery,
+    region: Region,
+    start: (u32, u32),
+    limit: Option<usize>,
+) -> Vec<(u32, u32)> {
+    if region.is_empty() {
+        return Vec::new();
+    }
+    let mut points = in_column_bands(region, |band| {
+        scan(band, start, limit, |x, y| {
+            query.matches(&haystack.get_pixel(x, y).0)
+        })
+    });
+    points.truncate(limit.unwrap_or(points.len()));
+    points
+}
+
+/// Visits the positions in `region` column by column from top to bottom, as
+/// `autopilot` does, collecting those for which `matches` returns `true`. The
+/// scan begins at `start`, whose row only applies to its own column; later
+/// columns are scanned from the top of `region`.
+fn scan<F>(region: Region, start: (u32, u32), limit: Option<usize>, matches: F) -> Vec<(u32, u32)>
+where
+    F: Fn(u32, u32) -> bool,
+{
+    let mut points = Vec::new();
+    for x in start.0.max(region.x0)..region.x1 {
+        let y0 = if x == start.0 {
+            start.1.max(region.y0)
+        } else {
+            region.y0
+        };
+        for y in y0..region.y1 {
+            if matches(x, y) {
+                points.push((x, y));
+                if limit.is_some_and(|limit| points.len() >= limit) {
+                    return points;
+                }
+            }
+        }
+    }
+    points
+}
+
+/// Splits the rows of `region` into one band per search thread, runs `search`
+/// on each band concurrently and concatenates the results in band order, so
+/// that they come out exactly as a serial row-major scan of `region` would
+/// return them.
+fn in_row_bands<T, F>(region: Region, search: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(Region) -> Vec<T> + Sync,
+{
+    in_bands(
+        region.y0,
+        region.y1,
+        |y0, y1| Region { y0, y1, ..region },
+        search,
+    )
+}
+
+/// Like `in_row_bands`, but splits `region` into bands of columns for
+/// column-major scans.
+fn in_column_bands<T, F>(region: Region, search: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(Region) -> Vec<T> + Sync,
+{
+    in_bands(
+        region.x0,
+        region.x1,
+        |x0, x1| Region { x0, x1, ..region },
+        search,
+    )
+}
+
+/// Splits the range from `start` to `end` into one band per search thread,
+/// making a region of each with `band`.
+fn in_bands<T, B, F>(start: u32, end: u32, band: B, search: F) -> Vec<T>
+where
+    T: Send,
+    B: Fn(u32, u32) -> Region,
+    F: Fn(Region) -> Vec<T> + Sync,
+{
+    let lines = (end - start) as usize;
+    let threads = search_threads().min(lines);
+    if threads <= 1 {
+        return search(band(start, end));
+    }
+    let band_lines = lines.div_ceil(threads) as u32;
+    let search = &search;
+    thread::scope(|scope| {
+        let handles: Vec<_> = (start..end)
+            .step_by(band_lines as usize)
+            .map(|line| {
+                let region = band(line, (line + band_lines).min(end));
+                scope.spawn(move || search(region))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("search thread panicked"))
+            .collect()
+    })
+}
+
+/// Criteria under which two matches of the same needle are considered to be
+/// the same on-screen instance.
+#[derive(Clone, Copy)]
+pub struct Suppression {
+    /// Matches whose positions are closer than this many pixels overlap.
+    pub min_distance: Option<f64>,
+    /// Matches whose intersection over union exceeds this overlap.
+    pub max_overlap: Option<f64>,
+}
+
+impl Suppression {
+    fn overlaps(&self, a: &Match, b: &Match, size: (u32, u32)) -> bool {
+        let dx = (a.x as f64 - b.x as f64).abs();
+        let dy = (a.y as f64 - b.y as f64).abs();
+        if self.min_distance.is_some_and(|d| dx.hypot(dy) < d) {
+            return true;
+        }
+        self.max_overlap.is_some_and(|max_overlap| {
+            let (width, height) = (size.0 as f64, size.1 as f64);
+            let intersection = (width - dx).max(0.0) * (height - dy).max(0.0);
+            let union = 2.0 * width * height - intersection;
+            union > 0.0 && intersection / union > max_overlap
+        })
+    }
+}
+
+/// Greedy non-maximum suppression: keeps the best scoring match of every
+/// group of overlapping matches of a needle of the given pixel size. Returns
+/// the indices of the kept matches from best to worst, with ties in their
+/// original order.
+pub fn suppress_overlapping(
+    matches: &[Match],
+    size: (u32, u32),
+    suppression: Suppression,
+) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..matches.len()).collect();
+    order.sort_by(|&a, &b| matches[b].score.total_cmp(&matches[a].score));
+    let mut kept: Vec<usize> = Vec::new();
+    for i in order {
+        if !kept
+            .iter()
+            .any(|&k| suppression.overlaps(&matches[k], &matches[i], size))
+        {
+            kept.push(i);
+        }
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    /// Returns an opaque image of pseudo-random colors.
+    fn noise(width: u32, height: u32, seed: u32) -> RgbaImage {
+        let mut state = seed;
+        RgbaImage::from_fn(width, height, |_, _| {
+            let mut channel = || {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            };
+            Rgba([channel(), channel(), channel(), 255])
+        })
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn exact_matches_score_one() {
+        let image = noise(20, 15, 1);
+        let needle = image::imageops::crop_imm(&image, 6, 4, 5, 3).to_image();
+        let haystack = Haystack::new(&image);
+        let template = Template::new(&needle, None);
+        for method in [MatchMethod::Ncc, MatchMethod::Sqdiff] {
+            assert!(close(score_at(&haystack, &template, 6, 4, method), 1.0));
+            assert!(score_at(&haystack, &template, 7, 4, method) < 1.0);
+        }
+    }
+
+    #[test]
+    fn flat_windows_have_defined_scores() {
+        let mut image = noise(12, 12, 2);
+        let flat = RgbaImage::from_pixel(4, 4, Rgba([90, 90, 90, 255]));
+        image::imageops::replace(&mut image, &flat, 0, 0);
+        let haystack = Haystack::new(&image);
+        let template = Template::new(&RgbaImage::from_pixel(4, 4, Rgba([30, 30, 30, 255])), None);
+        let flat_score = score_at(&haystack, &template, 0, 0, MatchMethod::Ncc);
+        let textured_score = score_at(&haystack, &template, 6, 6, MatchMethod::Ncc);
+        assert_eq!((flat_score, textured_score), (1.0, 0.0));
+        let textured = Template::new(&noise(4, 4, 3), None);
+        assert_eq!(score_at(&haystack, &textured, 0, 0, MatchMethod::Ncc), 0.0);
+        let sqdiff = score_at(&haystack, &template, 0, 0, MatchMethod::Sqdiff);
+        assert!(close(sqdiff, 1.0 - 60.0 / 255.0));
+    }
+
+    #[test]
+    fn best_match_is_found_at_needle_offset() {
+        let image = noise(50, 40, 4);
+        let needle = image::imageops::crop_imm(&image, 31, 22, 7, 6).to_image();
+        let haystack = Haystack::new(&image);
+        let template = Template::new(&needle, None);
+        let region = Region::new(&image, (7, 6), (0, 0, 50, 40));
+        for method in [MatchMethod::Ncc, MatchMethod::Sqdiff] {
+            let best = find_best(&haystack, &template, region, method).unwrap();
+            assert_eq!((best.x, best.y), (31, 22));
+            assert!(close(best.score, 1.0));
+        }
+    }
+
+    #[test]
+    fn parallel_searches_match_serial_ones() {
+        let mut haystack = RgbaImage::from_pixel(40, 30, Rgba([10, 20, 30, 255]));
+        let needle = RgbaImage::from_pixel(3, 2, Rgba([200, 100, 50, 255]));
+        for &(x, y) in &[(1, 1), (5, 20), (17, 3), (17, 9), (30, 25), (36, 0)] {
+            image::imageops::replace(&mut haystack, &needle, x, y);
+        }
+        let query = ColorQuery {
+            color: [200, 100, 50],
+            tolerance: 0.0,
+            alpha: None,
+            match_transparent: false,
+        };
+        let search = || {
+            let region = Region::new(&haystack, (3, 2), (0, 0, 40, 30));
+            let needles = find_every_needle(&haystack, &needle, None, region, (4, 5), 0.0, None);
+            let limited = find_every_needle(&haystack, &needle, None, region, (0, 0), 0.0, Some(3));
+            let region = Region::new(&haystack, (1, 1), (0, 0, 40, 30));
+            let colors = find_every_color(&haystack, query, region, (17, 4), None);
+            (needles, limited, colors)
+        };
+        set_search_threads(1);
+        let serial = search();
+        set_search_threads(4);
+        let parallel = search();
+        set_search_threads(1);
+        assert_eq!(serial.0.len(), 5);
+        assert_eq!(serial.1, vec![(1, 1), (5, 20), (17, 3)]);
+        assert_eq!(serial, parallel);
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Color statistics over a region of an image: per-channel histograms, and
+//! dominant colors found by a variance-minimizing variant of median cut.
+
+use crate::matching::Region;
+use image::RgbaImage;
+use std::collections::HashMap;
+
+/// Pixels with an alpha value below this are left out of the statistics.
+const ALPHA_THRESHOLD: u8 = 128;
+
+/// Returns the number of pixels in `region` with each value of the red, green
+/// and blue channels.
+pub fn histogram(image: &RgbaImage, region: Region) -> [[u64; 256]; 3] {
+    let mut histogram = [[0; 256]; 3];
+    for pixel in opaque_pixels(image, region) {
+        for (channel, counts) in histogram.iter_mut().enumerate() {
+            counts[pixel[channel] as usize] += 1;
+        }
+    }
+    histogram
+}
+
+/// Returns up to `k` colors representative of `region` along with the
+/// proportion of its pixels each stands for, from most to least common.
+pub fn dominant_colors(image: &RgbaImage, region: Region, k: usize) -> Vec<([u8; 3], f64)> {
+    let mut counts: HashMap<[u8; 3], u64> = HashMap::new();
+    for pixel in opaque_pixels(image, region) {
+        *counts.entry([pixel[0], pixel[1], pixel[2]]).or_insert(0) += 1;
+    }
+    let total: u64 = counts.values().sum();
+    if total == 0 {
+        return Vec::new();
+    }
+
+    let mut boxes = vec![ColorBox::new(counts.into_iter().collect())];
+    while boxes.len() < k {
+        let worst = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.colors.len() > 1)
+            .max_by(|(_, a), (_, b)| a.error.total_cmp(&b.error))
+            .map(|(index, _)| index);
+        let Some(index) = worst else {
+            break;
+        };
+        let (low, high) = boxes.swap_remove(index).split();
+        boxes.push(low);
+        boxes.push(high);
+    }
+
+    let mut colors: Vec<([u8; 3], f64)> = boxes
+        .iter()
+        .map(|b| (b.mean(), b.population as f64 / total as f64))
+        .collect();
+    colors.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    colors
+}
+
+fn opaque_pixels(image: &RgbaImage, region: Region) -> impl Iterator<Item = &[u8]> {
+    (region.y0..region.y1)
+        .flat_map(move |y| (region.x0..region.x1).map(move |x| &image.get_pixel(x, y).0[..]))
+        .filter(|pixel| pixel[3] >= ALPHA_THRESHOLD)
+}
+
+/// A group of distinct colors and the number of pixels having each.
+struct ColorBox {
+    colors: Vec<([u8; 3], u64)>,
+    population: u64,
+    /// Sum of squared distances of the box's pixels from their mean color.
+    error: f64,
+}
+
+impl ColorBox {
+    fn new(colors: Vec<([u8; 3], u64)>) -> ColorBox {
+        let population = colors.iter().map(|(_, count)| count).sum();
+        let error = Moments::of(&colors).error();
+        ColorBox {
+            colors,
+            population,
+            error,
+        }
+    }
+
+    /// Returns the channel whose values span the largest range.
+    fn widest_channel(&self) -> usize {
+        (0..3)
+            .max_by_key(|&channel| {
+                let values = self.colors.iter().map(|(color, _)| color[channel]);
+                let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+                (range, std::cmp::Reverse(channel))
+            })
+            .unwrap_or(0)
+    }
+
+    /// Splits the box in two along its widest channel, where the combined
+    /// error of the halves is least.
+    fn split(mut self) -> (ColorBox, ColorBox) {
+        let channel = self.widest_channel();
+        self.colors.sort_by_key(|(color, _)| *color);
+        self.colors.sort_by_key(|(color, _)| color[channel]);
+        let total = Moments::of(&self.colors);
+        let mut low = Moments::default();
+        let mut best = (f64::INFINITY, 1);
+        for (index, &(color, count)) in self.colors[..self.colors.len() - 1].iter().enumerate() {
+            low.add(color, count);
+            let error = low.error() + total.minus(&low).error();
+            if error < best.0 {
+                best = (error, index + 1);
+            }
+        }
+        let high = self.colors.split_off(best.1);
+        (ColorBox::new(self.colors), ColorBox::new(high))
+    }
+
+    /// Returns the average color of the box's pixels.
+    fn mean(&self) -> [u8; 3] {
+        let moments = Moments::of(&self.colors);
+        moments.sums.map(|sum| (sum / moments.count.max(1.0)).round() as u8)
+    }
+}
+
+/// Weighted sums of a group of colors, from which its error is derived.
+#[derive(Clone, Copy, Default)]
+struct Moments {
+    count: f64,
+    sums: [f64; 3],
+    squares: f64,
+}
+
+impl Moments {
+    fn of(colors: &[([u8; 3], u64)]) -> Moments {
+        let mut moments = Moments::default();
+        for &(color, count) in colors {
+            moments.add(color, count);
+        }
+        moments
+    }
+
+    fn add(&mut self, color: [u8; 3], count: u64) {
+        let count = count as f64;
+        self.count += count;
+        for (sum, &value) in self.sums.iter_mut().zip(color.iter()) {
+            let value = value as f64;
+            *sum += value * count;
+            self.squares += value * value * count;
+        }
+    }
+
+    fn minus(&self, other: &Moments) -> Moments {
+        Moments {
+            count: self.count - other.count,
+            sums: [0, 1, 2].map(|channel| self.sums[channel] - other.sums[channel]),
+            squares: self.squares - other.squares,
+        }
+    }
+
+    fn error(&self) -> f64 {
+        if self.count <= 0.0 {
+            return 0.0;
+        }
+        let mean_squares: f64 = self.sums.iter().map(|sum| sum * sum).sum::<f64>() / self.count;
+        (self.squares - mean_squares).max(0.0)
+    }
+}
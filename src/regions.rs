@@ -0,0 +1,93 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Grouping of pixels into connected regions.
+
+/// A group of 8-connected pixels. Coordinates are in pixels, and the maximums
+/// are inclusive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PixelRegion {
+    pub min_x: u32,
+    pub min_y: u32,
+    pub max_x: u32,
+    pub max_y: u32,
+    pub count: u64,
+    sum_x: u64,
+    sum_y: u64,
+}
+
+impl PixelRegion {
+    fn new(x: u32, y: u32) -> PixelRegion {
+        PixelRegion {
+            min_x: x,
+            min_y: y,
+            max_x: x,
+            max_y: y,
+            count: 0,
+            sum_x: 0,
+            sum_y: 0,
+        }
+    }
+
+    fn add(&mut self, x: u32, y: u32) {
+        self.min_x = self.min_x.min(x);
+        self.min_y = self.min_y.min(y);
+        self.max_x = self.max_x.max(x);
+        self.max_y = self.max_y.max(y);
+        self.count += 1;
+        self.sum_x += x as u64;
+        self.sum_y += y as u64;
+    }
+
+    pub fn width(&self) -> u32 {
+        self.max_x - self.min_x + 1
+    }
+
+    pub fn height(&self) -> u32 {
+        self.max_y - self.min_y + 1
+    }
+
+    /// Returns the mean position of the region's pixels.
+    pub fn centroid(&self) -> (f64, f64) {
+        let count = self.count.max(1) as f64;
+        (self.sum_x as f64 / count, self.sum_y as f64 / count)
+    }
+}
+
+/// Returns the 8-connected regions of pixels that are set in `pixels`, a
+/// row-major grid of the given width. Regions are ordered by their first
+/// pixel in row-major order.
+pub fn connected_regions(pixels: &[bool], width: u32) -> Vec<PixelRegion> {
+    let width = width as usize;
+    let height = pixels.len().checked_div(width).unwrap_or(0);
+    let mut visited = vec![false; pixels.len()];
+    let mut regions = Vec::new();
+    let mut stack = Vec::new();
+    for start in 0..pixels.len() {
+        if !pixels[start] || visited[start] {
+            continue;
+        }
+        let mut region = PixelRegion::new((start % width) as u32, (start / width) as u32);
+        visited[start] = true;
+        stack.push(start);
+        while let Some(index) = stack.pop() {
+            let (x, y) = (index % width, index / width);
+            region.add(x as u32, y as u32);
+            for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+                for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                    let neighbour = ny * width + nx;
+                    if pixels[neighbour] && !visited[neighbour] {
+                        visited[neighbour] = true;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+        }
+        regions.push(region);
+    }
+    regions
+}
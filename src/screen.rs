@@ -5,8 +5,10 @@
 // https://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use autopilot::geometry::Point;
-use crate::internal::{rgb_to_hex, FromImageError};
+use autopilot::geometry::{Point, Rect, Size};
+use crate::display;
+use crate::internal::{self, hex_to_rgb, rgb_to_hex, FromImageError, TimeoutError};
+use crate::matching::ColorQuery;
 use pyo3::prelude::*;
 use crate::image::Pixel;
 
@@ -38,16 +40,143 @@ fn is_point_visible(x: f64, y: f64) -> PyResult<bool> {
 ///
 /// only more efficient/convenient.
 ///
+/// If `display` is given, the point is relative to the top-left corner of the
+/// display with that id rather than the main screen.
+///
 /// Exceptions:
-///     - `ValueError` is thrown if the point out of bounds.
+///     - `ValueError` is thrown if the point out of bounds, or if there is no
+///       display with the given id.
 #[pyfunction]
-fn get_color(x: f64, y: f64) -> PyResult<u32> {
-    let point = Point::new(x, y);
-    let rgb = autopilot::screen::get_color(point).map_err(FromImageError::from)?;
+#[pyo3(signature = (x, y, display=None))]
+fn get_color(py: Python<'_>, x: f64, y: f64, display: Option<u32>) -> PyResult<u32> {
+    let rgb = if let Some(id) = display {
+        let bounds = display_with_id(id)?.bounds;
+        let rect = Rect::new(
+            Point::new(bounds.origin.x + x, bounds.origin.y + y),
+            Size::new(1.0, 1.0),
+        );
+        if !display::contains_rect(bounds, rect) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Point out of bounds {:?}",
+                (x, y)
+            )));
+        }
+        let bmp = py
+            .detach(|| display::capture(rect))
+            .map_err(FromImageError::from)?;
+        bmp.get_pixel(Point::new(0.0, 0.0))
+    } else {
+        let point = Point::new(x, y);
+        py.detach(|| autopilot::screen::get_color(point))
+            .map_err(FromImageError::from)?
+    };
     let channels = rgb.channels();
     Ok(rgb_to_hex(channels[0], channels[1], channels[2]))
 }
 
+/// Waits for the color at the given point of the main display to match
+/// `color`, checking every `interval` seconds.
+///
+/// Tolerance is defined as a float in the range from 0 to 1, where 0 is an
+/// exact match and 1 matches anything. The GIL is released while waiting.
+///
+/// Exceptions:
+///     - `TimeoutError` is thrown if the color doesn't match after `timeout`
+///       seconds.
+///     - `ValueError` is thrown if the point is out of bounds, `timeout` is
+///       negative or `interval` isn't positive.
+#[pyfunction]
+#[pyo3(signature = (x, y, color, timeout=10.0, interval=0.1, tolerance=None))]
+fn wait_for_color(
+    py: Python<'_>,
+    x: f64,
+    y: f64,
+    color: u32,
+    timeout: f64,
+    interval: f64,
+    tolerance: Option<f64>,
+) -> PyResult<()> {
+    let (r, g, b) = hex_to_rgb(color);
+    let query = ColorQuery {
+        color: [r, g, b],
+        tolerance: tolerance.unwrap_or(0.0),
+        alpha: None,
+        match_transparent: true,
+    };
+    let point = Point::new(x, y);
+    if !autopilot::screen::is_point_visible(point) {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Point out of bounds {:?}",
+            (x, y)
+        )));
+    }
+    internal::wait_until(py, timeout, interval, "color to match", || {
+        let rgb = autopilot::screen::get_color(point).map_err(FromImageError::from)?;
+        Ok(query.matches(rgb.channels()).then_some(()))
+    })
+}
+
+#[pyclass]
+/// A monitor making up part of the virtual desktop, as returned by
+/// `screen.displays()`.
+struct Display {
+    display: display::Display,
+}
+
+#[pymethods]
+impl Display {
+    /// Identifier to pass as the `display` argument of `screen.get_color`
+    /// and `bitmap.capture_screen`.
+    #[getter(id)]
+    fn id(&self) -> PyResult<u32> {
+        Ok(self.display.id)
+    }
+
+    /// Bounds of the display within the virtual desktop in points, in the
+    /// form of `((x, y), (width, height))`. The primary display's origin is
+    /// `(0, 0)` on macOS and Windows, so others may have negative coordinates.
+    #[getter(bounds)]
+    fn bounds(&self) -> PyResult<((f64, f64), (f64, f64))> {
+        let bounds = self.display.bounds;
+        Ok((
+            (bounds.origin.x, bounds.origin.y),
+            (bounds.size.width, bounds.size.height),
+        ))
+    }
+
+    /// How many pixels are in a point on this display. On Windows this is the
+    /// display's own DPI setting, but captures of it are taken at the system
+    /// scale.
+    #[getter(scale)]
+    fn scale(&self) -> PyResult<f64> {
+        Ok(self.display.scale)
+    }
+
+    /// Whether this is the primary display.
+    #[getter(is_primary)]
+    fn is_primary(&self) -> PyResult<bool> {
+        Ok(self.display.is_primary)
+    }
+}
+
+/// Returns a list of every active display, with the primary display first.
+#[pyfunction]
+fn displays(py: Python<'_>) -> PyResult<Vec<Display>> {
+    let displays = py.detach(display::displays);
+    Ok(displays
+        .into_iter()
+        .map(|display| Display { display })
+        .collect())
+}
+
+/// Returns the display with the given id, or raises `ValueError` if there is
+/// none.
+pub fn display_with_id(id: u32) -> PyResult<display::Display> {
+    display::display(id).ok_or_else(|| {
+        pyo3::exceptions::PyValueError::new_err(format!("Unknown display {}", id))
+    })
+}
+
 /// This module contains functions for working with the screen.
 #[pymodule]
 pub fn screen(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
@@ -55,5 +184,9 @@ pub fn screen(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_wrapped(wrap_pyfunction!(size))?;
     m.add_wrapped(wrap_pyfunction!(is_point_visible))?;
     m.add_wrapped(wrap_pyfunction!(get_color))?;
+    m.add_wrapped(wrap_pyfunction!(displays))?;
+    m.add_wrapped(wrap_pyfunction!(wait_for_color))?;
+    m.add_class::<Display>()?;
+    m.add("TimeoutError", m.py().get_type::<TimeoutError>())?;
     Ok(())
 }
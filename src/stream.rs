@@ -0,0 +1,234 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Continuous capture of frames at a target rate in a background thread,
+//! either into a bounded buffer that drops the oldest frames when full, or
+//! straight to disk.
+
+use autopilot::bitmap::Bitmap;
+use image::{ImageError, ImageResult};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub struct Frame {
+    /// Seconds between the stream starting and the frame being captured.
+    pub timestamp: f64,
+    pub bitmap: Bitmap,
+}
+
+/// The outcome of waiting for the next frame of a stream.
+pub enum Next {
+    Frame(Frame),
+    /// No frame arrived in time, but the stream is still running.
+    Pending,
+    /// The stream has stopped and every frame has been consumed. Holds the
+    /// error that stopped it, if any, which is only returned once.
+    Finished(Option<ImageError>),
+}
+
+pub struct Stream {
+    shared: Arc<Shared>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    /// The time frame timestamps are relative to.
+    started: Instant,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    frames: VecDeque<Frame>,
+    capacity: usize,
+    captured: u64,
+    dropped: u64,
+    running: bool,
+    error: Option<ImageError>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Stream {
+    /// Starts calling `capture` once every `period`. Frames are kept in a
+    /// buffer of `capacity` frames, or saved as PNG files in `directory` if
+    /// given.
+    pub fn start<F>(
+        period: Duration,
+        capacity: usize,
+        directory: Option<PathBuf>,
+        capture: F,
+    ) -> Stream
+    where
+        F: FnMut() -> ImageResult<Bitmap> + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                frames: VecDeque::with_capacity(capacity),
+                capacity,
+                captured: 0,
+                dropped: 0,
+                running: true,
+                error: None,
+            }),
+            changed: Condvar::new(),
+        });
+        let started = Instant::now();
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || run(&shared, started, period, directory, capture))
+        };
+        Stream {
+            shared,
+            thread: Mutex::new(Some(thread)),
+            started,
+        }
+    }
+
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    /// Waits up to `timeout` for the oldest buffered frame.
+    pub fn next_frame(&self, timeout: Duration) -> Next {
+        let state = self.shared.lock();
+        let (mut state, _) = self
+            .shared
+            .changed
+            .wait_timeout_while(state, timeout, |state| {
+                state.frames.is_empty() && state.running
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(frame) = state.frames.pop_front() {
+            Next::Frame(frame)
+        } else if state.running {
+            Next::Pending
+        } else {
+            Next::Finished(state.error.take())
+        }
+    }
+
+    /// Removes and returns every buffered frame without waiting.
+    pub fn drain(&self) -> Vec<Frame> {
+        self.shared.lock().frames.drain(..).collect()
+    }
+
+    /// Stops capturing and waits for the capture thread to finish. Frames
+    /// already buffered can still be consumed.
+    pub fn stop(&self) {
+        self.shared.lock().running = false;
+        self.shared.changed.notify_all();
+        let thread = self
+            .thread
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.shared.lock().running
+    }
+
+    /// Number of frames captured so far.
+    pub fn captured(&self) -> u64 {
+        self.shared.lock().captured
+    }
+
+    /// Number of frames lost so far, either because the buffer was full or
+    /// because capturing could not keep up with the target rate.
+    pub fn dropped(&self) -> u64 {
+        self.shared.lock().dropped
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run<F>(
+    shared: &Shared,
+    start: Instant,
+    period: Duration,
+    directory: Option<PathBuf>,
+    mut capture: F,
+) where
+    F: FnMut() -> ImageResult<Bitmap>,
+{
+    let mut deadline = start;
+    loop {
+        let timestamp = start.elapsed().as_secs_f64();
+        let index = shared.lock().captured;
+        let result = capture().and_then(|bitmap| {
+            if let Some(directory) = &directory {
+                let name = format!("frame_{:06}_{:08}.png", index, (timestamp * 1000.0) as u64);
+                bitmap.image.save(directory.join(name))?;
+            }
+            Ok(bitmap)
+        });
+
+        let mut state = shared.lock();
+        if !state.running {
+            return;
+        }
+        match result {
+            Ok(bitmap) => {
+                if directory.is_none() {
+                    if state.frames.len() >= state.capacity {
+                        state.frames.pop_front();
+                        state.dropped += 1;
+                    }
+                    state.frames.push_back(Frame { timestamp, bitmap });
+                }
+                state.captured += 1;
+            }
+            Err(err) => {
+                state.error = Some(err);
+                state.running = false;
+                shared.changed.notify_all();
+                return;
+            }
+        }
+        shared.changed.notify_all();
+
+        // Skip any capture times missed while this frame was being captured,
+        // rather than trying to catch up.
+        let Some(next) = deadline.checked_add(period) else {
+            // The next frame is due too far in the future to be represented,
+            // so just wait to be stopped.
+            drop(shared.changed.wait_while(state, |state| state.running));
+            return;
+        };
+        deadline = next;
+        let now = Instant::now();
+        if deadline < now {
+            let missed = ((now - deadline).as_secs_f64() / period.as_secs_f64()).ceil() as u32;
+            state.dropped += missed as u64;
+            deadline += period * missed;
+        }
+        let (state, _) = shared
+            .changed
+            .wait_timeout_while(state, deadline.saturating_duration_since(now), |state| {
+                state.running
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        if !state.running {
+            return;
+        }
+    }
+}